@@ -1,13 +1,32 @@
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
-use axum::{routing::get, Router};
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Router,
+};
 use cloud_netconfig::*;
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex, Notify};
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Registry};
+
+/// Everything a configuration reload needs to touch
+struct ReloadContext {
+    config: Mutex<conf::Config>,
+    env: Arc<Mutex<provider::Environment>>,
+    refresh_interval: watch::Sender<Duration>,
+    refresh_notify: Arc<Notify>,
+    log_filter: reload::Handle<EnvFilter, Registry>,
+}
 
-async fn cloud_network_begin(env: Arc<Mutex<provider::Environment>>) -> anyhow::Result<()> {
+async fn cloud_network_begin(env: Arc<Mutex<provider::Environment>>) -> anyhow::Result<provider::Diff> {
     let mut env_guard = env.lock().await;
 
     tracing::debug!("Connecting to metadata server ({}) ...", env_guard.kind);
@@ -22,22 +41,249 @@ async fn cloud_network_begin(env: Arc<Mutex<provider::Environment>>) -> anyhow::
 
     provider::save_metadata(&env_guard).await?;
 
-    Ok(())
+    Ok(env_guard.last_diff.clone())
+}
+
+/// Re-reads the config file and applies what can change at runtime. On error
+/// the current configuration stays in place.
+async fn reload_config(ctx: &ReloadContext, force: bool) -> anyhow::Result<Vec<String>> {
+    let new_config = conf::Config::load().context("Invalid configuration")?;
+    let log_filter = EnvFilter::try_new(&new_config.logging.level).context("Invalid log level")?;
+    let mut config = ctx.config.lock().await;
+    let mut changes = Vec::new();
+
+    // Migrating can fail, so it goes first and nothing else is applied then
+    let table_base = new_config.network.routing.table_base;
+    let table_base_changed = force || table_base != config.network.routing.table_base;
+    if table_base_changed {
+        let mut env_guard = ctx.env.lock().await;
+        provider::migrate_route_table(&mut env_guard, table_base).await?;
+        changes.push(format!("table base '{}'", table_base));
+    }
+
+    // Supplementary links are not reconfigured by the refresh, a new table
+    // base needs them installed again
+    let supplementary = new_config.get_supplementary_interfaces();
+    let previous = config.get_supplementary_interfaces();
+    if table_base_changed || supplementary != previous {
+        let removed: Vec<&str> = previous
+            .split_whitespace()
+            .filter(|name| !supplementary.split_whitespace().any(|n| n == *name))
+            .collect();
+
+        let mut env_guard = ctx.env.lock().await;
+        provider::remove_supplementary_links(&mut env_guard, &removed).await?;
+        provider::configure_supplementary_links(&mut env_guard, &supplementary).await?;
+
+        if force || supplementary != previous {
+            changes.push(format!("supplementary interfaces '{}'", supplementary));
+        }
+    }
+
+    if force || new_config.metadata.refresh_interval != config.metadata.refresh_interval {
+        ctx.refresh_interval.send_replace(new_config.get_refresh_duration());
+        changes.push(format!("refresh interval '{}'", new_config.metadata.refresh_interval));
+    }
+
+    if force || new_config.logging.level != config.logging.level {
+        ctx.log_filter.reload(log_filter).context("Failed to change log level")?;
+        changes.push(format!("log level '{}'", new_config.logging.level));
+    }
+
+    *config = new_config;
+
+    // Reconfigure right away so a new table base is populated
+    if !changes.is_empty() {
+        ctx.refresh_notify.notify_one();
+    }
+
+    Ok(changes)
+}
+
+async fn reload_endpoint(ctx: Arc<ReloadContext>, params: HashMap<String, String>) -> axum::response::Response {
+    let force = params.get("force").map(|v| v == "true").unwrap_or(false);
+
+    match reload_config(&ctx, force).await {
+        Ok(changes) => {
+            tracing::info!("Configuration reloaded, changed: [{}]", changes.join(", "));
+            web::json_response(&serde_json::json!({ "status": "ok", "changes": changes }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to reload configuration, keeping current one: {:#}", e);
+            web::error_response(StatusCode::BAD_REQUEST, &format!("{:#}", e))
+        }
+    }
+}
+
+/// Control endpoints answer with the changes made, or the ones pending while
+/// management is paused
+fn diff_response(env: &provider::Environment, diff: &provider::Diff) -> axum::response::Response {
+    web::json_response(&serde_json::json!({ "status": "ok", "paused": env.paused, "diff": diff }))
+}
+
+async fn refresh_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::response::Response {
+    match cloud_network_begin(env.clone()).await {
+        Ok(diff) => diff_response(&*env.lock().await, &diff),
+        Err(e) => {
+            tracing::error!("Error during requested refresh: {:#}", e);
+            web::error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{:#}", e))
+        }
+    }
+}
+
+async fn link_endpoint(env: Arc<Mutex<provider::Environment>>, name: String, action: String) -> axum::response::Response {
+    let mut env_guard = env.lock().await;
+
+    let result = match action.as_str() {
+        "reconfigure" => provider::reconfigure_link(&mut env_guard, &name).await,
+        "manage" => provider::manage_link(&mut env_guard, &name).await,
+        "unmanage" => provider::unmanage_link(&mut env_guard, &name).map(|_| provider::Diff::default()),
+        _ => return web::error_response(StatusCode::NOT_FOUND, &format!("Unknown action '{}'", action)),
+    };
+
+    match result {
+        Ok(diff) => diff_response(&env_guard, &diff),
+        Err(e) => {
+            tracing::error!("Failed to {} link='{}': {:#}", action, name, e);
+            web::error_response(StatusCode::BAD_REQUEST, &format!("{:#}", e))
+        }
+    }
+}
+
+/// Changes a configuration pass would make from the metadata fetched last,
+/// computed the same way as for a real pass but not applied
+async fn plan_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::response::Response {
+    let mut env_guard = env.lock().await;
+
+    match provider::plan_network_metadata(&mut env_guard).await {
+        Ok(diff) => diff_response(&env_guard, &diff),
+        Err(e) => {
+            tracing::error!("Failed to plan network configuration: {:#}", e);
+            web::error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{:#}", e))
+        }
+    }
+}
+
+async fn pause_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::response::Response {
+    let mut env_guard = env.lock().await;
+    env_guard.paused = true;
+    tracing::info!("Network management paused");
+    diff_response(&env_guard, &provider::Diff::default())
+}
+
+async fn resume_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::response::Response {
+    env.lock().await.paused = false;
+    tracing::info!("Network management resumed");
+    refresh_endpoint(env).await
 }
 
 async fn health_check() -> &'static str {
     "OK"
 }
 
+async fn metrics_endpoint() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::METRICS.render(),
+    )
+}
+
 async fn status_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::Json<serde_json::Value> {
     let env_guard = env.lock().await;
     axum::Json(serde_json::json!({
         "status": "running",
         "provider": env_guard.kind.as_str(),
-        "version": conf::VERSION
+        "version": conf::VERSION,
+        "paused": env_guard.paused,
+        "unmanaged": env_guard.unmanaged,
     }))
 }
 
+async fn cloud_system_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::response::Response {
+    let env_guard = env.lock().await;
+    match env_guard.provider.system_metadata() {
+        Some(system) => web::json_response(&system),
+        None => web::error_response(StatusCode::SERVICE_UNAVAILABLE, "Metadata not fetched yet"),
+    }
+}
+
+async fn cloud_network_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::response::Response {
+    let env_guard = env.lock().await;
+    let links: BTreeMap<String, serde_json::Value> = env_guard
+        .links
+        .links_by_mac
+        .values()
+        .filter_map(|link| Some((link.name.clone(), env_guard.provider.link_metadata(link)?)))
+        .collect();
+
+    web::json_response(&links)
+}
+
+async fn cloud_link_endpoint(env: Arc<Mutex<provider::Environment>>, name: String) -> axum::response::Response {
+    let env_guard = env.lock().await;
+    match provider::find_link_by_name(&env_guard, &name).and_then(|link| env_guard.provider.link_metadata(link)) {
+        Some(meta) => web::json_response(&meta),
+        None => web::error_response(StatusCode::NOT_FOUND, &format!("No metadata for link '{}'", name)),
+    }
+}
+
+async fn network_links_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::response::Response {
+    let env_guard = env.lock().await;
+    match provider::network_status(&env_guard).await {
+        Ok(status) => web::json_response(&status),
+        Err(e) => web::error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{:#}", e)),
+    }
+}
+
+async fn network_routes_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::response::Response {
+    let env_guard = env.lock().await;
+    match provider::network_status(&env_guard).await {
+        Ok(status) => {
+            let routes: Vec<serde_json::Value> = status
+                .into_iter()
+                .map(|link| {
+                    serde_json::json!({
+                        "link": link.name,
+                        "ifindex": link.if_index,
+                        "table": link.table,
+                        "managed": { "routes": link.managed.routes, "local_routes": link.managed.local_routes },
+                        "kernel": { "routes": link.kernel.routes, "local_routes": link.kernel.local_routes },
+                    })
+                })
+                .collect();
+            web::json_response(&routes)
+        }
+        Err(e) => web::error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{:#}", e)),
+    }
+}
+
+async fn network_rules_endpoint(env: Arc<Mutex<provider::Environment>>) -> axum::response::Response {
+    let env_guard = env.lock().await;
+    match provider::network_status(&env_guard).await {
+        Ok(status) => {
+            let rules: Vec<serde_json::Value> = status
+                .into_iter()
+                .map(|link| {
+                    serde_json::json!({
+                        "link": link.name,
+                        "ifindex": link.if_index,
+                        "table": link.table,
+                        "managed": link.managed.rules,
+                        "kernel": link.kernel.rules,
+                    })
+                })
+                .collect();
+            web::json_response(&rules)
+        }
+        Err(e) => web::error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{:#}", e)),
+    }
+}
+
+async fn scheduled_events_endpoint(events: provider::ScheduledEventsState) -> axum::response::Response {
+    let events_guard = events.lock().await;
+    web::json_response(&*events_guard)
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Parse configuration
@@ -47,6 +293,9 @@ async fn main() -> anyhow::Result<()> {
     let filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::new(&config.logging.level));
 
+    // Log level can be changed on reload
+    let (filter, log_filter) = reload::Layer::new(filter);
+
     match config.logging.format.as_str() {
         "json" => {
             let layer = if config.logging.timestamps {
@@ -55,8 +304,8 @@ async fn main() -> anyhow::Result<()> {
                 fmt::layer().json().without_time()
             };
             tracing_subscriber::registry()
-                .with(layer)
                 .with(filter)
+                .with(layer)
                 .init();
         }
         _ => {
@@ -66,8 +315,8 @@ async fn main() -> anyhow::Result<()> {
                 fmt::layer().without_time()
             };
             tracing_subscriber::registry()
-                .with(layer)
                 .with(filter)
+                .with(layer)
                 .init();
         }
     }
@@ -75,6 +324,10 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("cloud-netconfig v{}", conf::VERSION);
     tracing::debug!("Configuration loaded: {:?}", config);
 
+    // Catch SIGHUP right away, systemctl reload during startup would kill the
+    // daemon otherwise. Signals received until the handler runs are kept.
+    let mut hangup = signal(SignalKind::hangup())?;
+
     // Detect cloud environment
     let kind = if config.cloud.auto_detect {
         cloud::detect_cloud()
@@ -130,6 +383,11 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
+    // Pick up what a previous run installed, so stale objects get removed
+    if let Err(e) = provider::restore_ledger(&mut env, config.features.cleanup_stale).await {
+        tracing::warn!("Failed to restore state ledger: {}", e);
+    }
+
     // Wrap environment in Arc<Mutex> for sharing
     let env = Arc::new(Mutex::new(env));
 
@@ -147,32 +405,146 @@ async fn main() -> anyhow::Result<()> {
         let supplementary = config.get_supplementary_interfaces();
         if !supplementary.is_empty() {
             tracing::info!("Configuring supplementary interfaces: {}", supplementary);
-            network::configure_supplementary_links(&supplementary).await.ok();
+            let mut env_guard = env.lock().await;
+            provider::configure_supplementary_links(&mut env_guard, &supplementary).await.ok();
         }
     }
 
+    // Refresh can be triggered early, e.g. by metadata change notifications
+    let refresh_notify = Arc::new(Notify::new());
+
+    // The refresh interval can change on reload
+    let (refresh_interval, mut refresh_interval_rx) = watch::channel(config.get_refresh_duration());
+
+    if kind == cloud::CloudProvider::GCP && config.cloud.gcp.wait_for_change {
+        let client = web::HttpClient::from_config(&config).with_metrics_provider(kind.as_str());
+        tokio::spawn(provider::watch_metadata(
+            provider::GCP::metadata_client(&config.cloud.gcp, client),
+            refresh_notify.clone(),
+            refresh_interval_rx.clone(),
+        ));
+    }
+
+    let scheduled_events = provider::ScheduledEventsState::default();
+
+    if kind == cloud::CloudProvider::Azure && config.cloud.azure.scheduled_events.enabled {
+        tokio::spawn(provider::watch_scheduled_events(config.clone(), scheduled_events.clone()));
+    }
+
     // Start periodic refresh timer
-    let refresh_duration = config.get_refresh_duration();
     let env_clone = env.clone();
+    let notify_clone = refresh_notify.clone();
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(refresh_duration);
+        let mut interval = tokio::time::interval(*refresh_interval_rx.borrow());
         loop {
-            interval.tick().await;
-            tracing::debug!("Periodic metadata refresh triggered");
+            tokio::select! {
+                _ = interval.tick() => tracing::debug!("Periodic metadata refresh triggered"),
+                _ = notify_clone.notified() => tracing::debug!("Metadata refresh triggered"),
+                Ok(()) = refresh_interval_rx.changed() => {
+                    let duration = *refresh_interval_rx.borrow();
+                    tracing::info!("Metadata refresh interval changed to {:?}", duration);
+                    interval = tokio::time::interval_at(tokio::time::Instant::now() + duration, duration);
+                    continue;
+                }
+            }
             if let Err(e) = cloud_network_begin(env_clone.clone()).await {
                 tracing::error!("Error during periodic refresh: {}", e);
             }
         }
     });
 
+    let listen_addr = config.get_listen_addr();
+    let unix_socket = config.server.unix_socket.clone();
+    let socket_access = Arc::new(web::SocketAccess::from_config(&config));
+    let watchdog = config.security.watchdog.clone();
+    let watchdog_interval = config.get_watchdog_interval();
+
+    let reload_ctx = Arc::new(ReloadContext {
+        config: Mutex::new(config),
+        env: env.clone(),
+        refresh_interval,
+        refresh_notify: refresh_notify.clone(),
+        log_filter,
+    });
+
+    // Reload configuration on SIGHUP (systemctl reload)
+    let ctx = reload_ctx.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            tracing::info!("Received SIGHUP, reloading configuration ...");
+            match reload_config(&ctx, false).await {
+                Ok(changes) => tracing::info!("Configuration reloaded, changed: [{}]", changes.join(", ")),
+                Err(e) => tracing::error!("Failed to reload configuration, keeping current one: {:#}", e),
+            }
+        }
+    });
+
+    let shutdown_ctx = reload_ctx.clone();
+
     // Setup HTTP server
     let env_for_status = env.clone();
+    let env_for_system = env.clone();
+    let env_for_network = env.clone();
+    let env_for_link = env.clone();
+    let env_for_links = env.clone();
+    let env_for_routes = env.clone();
+    let env_for_rules = env.clone();
+    let env_for_plan = env.clone();
+    let env_for_refresh = env.clone();
+    let env_for_control = env.clone();
+    let env_for_pause = env.clone();
+    let env_for_resume = env.clone();
     let app = Router::new()
         .route("/health", get(health_check))
+        .route("/metrics", get(metrics_endpoint))
         .route("/api/status", get(move || status_endpoint(env_for_status.clone())))
-        .route("/api/cloud/status", get(health_check));
+        .route("/api/cloud/status", get(health_check))
+        .route("/api/cloud/system", get(move || cloud_system_endpoint(env_for_system.clone())))
+        .route("/api/cloud/network", get(move || cloud_network_endpoint(env_for_network.clone())))
+        .route(
+            "/api/cloud/network/:link",
+            get(move |Path(name): Path<String>| cloud_link_endpoint(env_for_link.clone(), name)),
+        )
+        .route("/api/network/links", get(move || network_links_endpoint(env_for_links.clone())))
+        .route("/api/network/routes", get(move || network_routes_endpoint(env_for_routes.clone())))
+        .route("/api/network/rules", get(move || network_rules_endpoint(env_for_rules.clone())))
+        .route("/api/plan", get(move || plan_endpoint(env_for_plan.clone())))
+        .route(
+            "/api/cloud/scheduledevents",
+            get(move || scheduled_events_endpoint(scheduled_events.clone())),
+        )
+        .route("/api/refresh", post(move || refresh_endpoint(env_for_refresh.clone())))
+        .route(
+            "/api/links/:name/:action",
+            post(move |Path((name, action)): Path<(String, String)>| {
+                link_endpoint(env_for_control.clone(), name, action)
+            }),
+        )
+        .route("/api/pause", post(move || pause_endpoint(env_for_pause.clone())))
+        .route("/api/resume", post(move || resume_endpoint(env_for_resume.clone())))
+        .route(
+            "/api/reload",
+            post(move |Query(params): Query<HashMap<String, String>>| {
+                reload_endpoint(reload_ctx.clone(), params)
+            }),
+        );
+
+    // Changes are authorised by peer credentials, which only the socket has,
+    // so the TCP listener is read-only whether the socket is enabled or not
+    let app = app.layer(axum::middleware::from_fn_with_state(socket_access, web::authorize));
+
+    if unix_socket.enabled {
+        let listener = web::bind_unix(&unix_socket.path)?;
+        tracing::info!("HTTP API server listening on {}", unix_socket.path);
+
+        let unix_app = app.clone();
+        tokio::spawn(async move {
+            if let Err(e) = web::serve_unix(listener, unix_app).await {
+                tracing::error!("Unix socket API server stopped: {}", e);
+            }
+        });
+    }
 
-    let listen_addr = config.get_listen_addr();
     let addr: SocketAddr = listen_addr.parse()?;
 
     tracing::info!("HTTP API server listening on {}", listen_addr);
@@ -183,8 +555,7 @@ async fn main() -> anyhow::Result<()> {
     }
 
     // Start watchdog thread if enabled
-    if config.security.watchdog.enabled {
-        let watchdog_interval = config.get_watchdog_interval();
+    if watchdog.enabled {
         tracing::info!("Systemd watchdog enabled with interval: {:?}", watchdog_interval);
 
         tokio::spawn(async move {
@@ -196,11 +567,13 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
-    // Setup signal handlers for graceful shutdown
-    let shutdown_signal = async {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to install CTRL+C signal handler");
+    // Setup signal handlers for graceful shutdown, systemd stops with SIGTERM
+    let mut terminate = signal(SignalKind::terminate())?;
+    let shutdown_signal = async move {
+        tokio::select! {
+            r = tokio::signal::ctrl_c() => r.expect("Failed to install CTRL+C signal handler"),
+            _ = terminate.recv() => {}
+        }
         tracing::info!("Received shutdown signal, stopping...");
     };
 
@@ -210,6 +583,17 @@ async fn main() -> anyhow::Result<()> {
         .with_graceful_shutdown(shutdown_signal)
         .await?;
 
+    if unix_socket.enabled {
+        let _ = std::fs::remove_file(&unix_socket.path);
+    }
+
+    // Keep the environment locked until exit so no refresh or network event
+    // reinstalls what teardown removes
+    let mut env_guard = env.lock().await;
+    if shutdown_ctx.config.lock().await.network.on_shutdown == "remove" {
+        provider::teardown_network(&mut env_guard).await;
+    }
+
     // Notify systemd that we're stopping
     let _ = libsystemd::daemon::notify(false, &[libsystemd::daemon::NotifyState::Stopping]);
 
@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use axum::http::Method;
 use clap::{Parser, Subcommand};
 use cloud_netconfig::*;
 
@@ -39,22 +40,62 @@ enum Commands {
         force: bool,
     },
 
+    /// Show the changes the daemon would make, without applying them
+    Plan,
+
+    /// Fetch metadata and reconfigure the network now
+    Refresh,
+
+    /// Reconfigure a link, or exclude it from management and bring it back
+    Link {
+        /// What to do with the link
+        #[arg(value_parser = ["reconfigure", "unmanage", "manage"])]
+        action: String,
+
+        /// Link name, e.g. eth1
+        name: String,
+    },
+
+    /// Stop applying network changes until resumed
+    Pause,
+
+    /// Resume applying network changes and reconfigure
+    Resume,
+
     /// Show daemon version
     Version,
 }
 
-async fn fetch_metadata(endpoint: &str) -> anyhow::Result<serde_json::Value> {
-    let config = conf::Config::parse().unwrap_or_default();
+/// Talks to the daemon over its Unix socket when it exists, changes are only
+/// accepted there, and falls back to the TCP listener otherwise
+async fn request_daemon(method: Method, endpoint: &str) -> anyhow::Result<serde_json::Value> {
+    let config = conf::Config::parse()
+        .map_err(|e| anyhow::anyhow!("Failed to read configuration: {}", e))?;
+
+    let socket = &config.server.unix_socket.path;
+    if std::path::Path::new(socket).exists() {
+        let body = web::unix_request(socket, method, endpoint).await?;
+        return Ok(serde_json::from_slice(&body)?);
+    }
+
+    if method != Method::GET {
+        anyhow::bail!(
+            "The daemon only accepts changes on its Unix socket '{}', enable server.unix_socket",
+            socket
+        );
+    }
+
     let listen_addr = config.get_listen_addr();
     let url = format!("http://{}{}", listen_addr, endpoint);
-
-    let client = reqwest::Client::new();
-    let response = client.get(&url).send().await?;
-    let data = response.json::<serde_json::Value>().await?;
+    let data = reqwest::get(&url).await?.json::<serde_json::Value>().await?;
 
     Ok(data)
 }
 
+async fn fetch_metadata(endpoint: &str) -> anyhow::Result<serde_json::Value> {
+    request_daemon(Method::GET, endpoint).await
+}
+
 async fn show_system_status() -> anyhow::Result<()> {
     let kind = cloud::detect_cloud();
 
@@ -82,6 +123,24 @@ async fn show_system_status() -> anyhow::Result<()> {
                     }
                 }
             }
+
+            if let Ok(data) = fetch_metadata("/api/cloud/scheduledevents").await {
+                let events = data.get("Events").and_then(|e| e.as_array()).cloned().unwrap_or_default();
+                if !events.is_empty() {
+                    println!();
+                    println!("Scheduled Events:");
+                    for event in events {
+                        let field = |key: &str| event.get(key).and_then(|v| v.as_str()).unwrap_or("").to_string();
+                        println!("      Event Id: {}", field("EventId"));
+                        println!("          Type: {}", field("EventType"));
+                        println!("        Status: {}", field("EventStatus"));
+                        println!("    Not Before: {}", field("NotBefore"));
+                        if !field("Description").is_empty() {
+                            println!("   Description: {}", field("Description"));
+                        }
+                    }
+                }
+            }
         }
         cloud::CloudProvider::AWS => {
             if let Ok(data) = fetch_metadata("/api/cloud/system").await {
@@ -116,6 +175,57 @@ async fn show_system_status() -> anyhow::Result<()> {
                 }
             }
         }
+        cloud::CloudProvider::Alibaba => {
+            if let Ok(data) = fetch_metadata("/api/cloud/system").await {
+                if let Some(obj) = data.as_object() {
+                    if let Some(instance_id) = obj.get("instance_id") {
+                        println!("   Instance Id: {}", instance_id.as_str().unwrap_or(""));
+                    }
+                    if let Some(instance_type) = obj.get("instance_type") {
+                        println!(" Instance Type: {}", instance_type.as_str().unwrap_or(""));
+                    }
+                    if let Some(region_id) = obj.get("region_id") {
+                        println!("        Region: {}", region_id.as_str().unwrap_or(""));
+                    }
+                    if let Some(zone_id) = obj.get("zone_id") {
+                        println!("          Zone: {}", zone_id.as_str().unwrap_or(""));
+                    }
+                }
+            }
+        }
+        cloud::CloudProvider::Oracle => {
+            if let Ok(data) = fetch_metadata("/api/cloud/system").await {
+                if let Some(obj) = data.as_object() {
+                    if let Some(display_name) = obj.get("displayName") {
+                        println!("          Name: {}", display_name.as_str().unwrap_or(""));
+                    }
+                    if let Some(shape) = obj.get("shape") {
+                        println!("         Shape: {}", shape.as_str().unwrap_or(""));
+                    }
+                    if let Some(region) = obj.get("region") {
+                        println!("        Region: {}", region.as_str().unwrap_or(""));
+                    }
+                    if let Some(availability_domain) = obj.get("availabilityDomain") {
+                        println!("  Avail Domain: {}", availability_domain.as_str().unwrap_or(""));
+                    }
+                }
+            }
+        }
+        cloud::CloudProvider::DigitalOcean => {
+            if let Ok(data) = fetch_metadata("/api/cloud/system").await {
+                if let Some(obj) = data.as_object() {
+                    if let Some(droplet_id) = obj.get("droplet_id") {
+                        println!("    Droplet Id: {}", droplet_id);
+                    }
+                    if let Some(hostname) = obj.get("hostname") {
+                        println!("      Hostname: {}", hostname.as_str().unwrap_or(""));
+                    }
+                    if let Some(region) = obj.get("region") {
+                        println!("        Region: {}", region.as_str().unwrap_or(""));
+                    }
+                }
+            }
+        }
         _ => {
             println!("No detailed information available");
         }
@@ -125,7 +235,8 @@ async fn show_system_status() -> anyhow::Result<()> {
 }
 
 async fn show_network_status() -> anyhow::Result<()> {
-    let links = network::acquire_links().await?;
+    let nl = network::NetlinkContext::new();
+    let links = network::acquire_links(&nl).await?;
 
     println!("Network Interfaces:");
     println!();
@@ -135,8 +246,11 @@ async fn show_network_status() -> anyhow::Result<()> {
         println!("MAC Address: {}", link.mac);
         println!("      State: {}", link.oper_state);
         println!("        MTU: {}", link.mtu);
+        if !link.driver.is_empty() {
+            println!("     Driver: {}", link.driver);
+        }
 
-        if let Ok(addresses) = network::get_ipv4_addresses(&link.name).await {
+        if let Ok(addresses) = network::get_ipv4_addresses(&nl, &link.name).await {
             for (addr, _) in addresses {
                 println!(" Private IP: {}", addr);
             }
@@ -145,6 +259,15 @@ async fn show_network_status() -> anyhow::Result<()> {
         println!();
     }
 
+    for link in links.enslaved_by_index.values() {
+        println!("       Name: {} (enslaved to ifindex {}, unmanaged)", link.name, link.master.unwrap_or_default());
+        println!("MAC Address: {}", link.mac);
+        if !link.driver.is_empty() {
+            println!("     Driver: {}", link.driver);
+        }
+        println!();
+    }
+
     Ok(())
 }
 
@@ -196,6 +319,33 @@ async fn apply_config(config_path: &str, dry_run: bool) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn post_daemon(endpoint: &str) -> anyhow::Result<serde_json::Value> {
+    request_daemon(Method::POST, endpoint).await
+}
+
+/// Asks the daemon to reload the same way the unit's ExecReload does, used
+/// when there is no Unix socket to carry the request
+fn signal_daemon_reload() -> anyhow::Result<i32> {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    let pid = std::fs::read_dir("/proc")?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .find(|pid| {
+            // The kernel truncates comm to 15 characters
+            std::fs::read_to_string(format!("/proc/{}/comm", pid))
+                .map(|comm| comm.trim() == &"cloud-netconfigd"[..15])
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| anyhow::anyhow!("cloud-netconfigd is not running"))?;
+
+    kill(Pid::from_raw(pid), Signal::SIGHUP)
+        .map_err(|e| anyhow::anyhow!("Failed to signal cloud-netconfigd (pid {}): {}", pid, e))?;
+
+    Ok(pid)
+}
+
 async fn reload_daemon(force: bool) -> anyhow::Result<()> {
     println!("Reloading daemon configuration...");
 
@@ -203,9 +353,97 @@ async fn reload_daemon(force: bool) -> anyhow::Result<()> {
         println!("Force reload requested");
     }
 
-    // Send reload signal to daemon (would need IPC implementation)
-    println!("\nCurrently, please reload using:");
-    println!("  sudo systemctl reload cloud-netconfigd");
+    let config = conf::Config::parse()
+        .map_err(|e| anyhow::anyhow!("Failed to read configuration: {}", e))?;
+    if !std::path::Path::new(&config.server.unix_socket.path).exists() {
+        if force {
+            anyhow::bail!("Force reload needs the daemon's Unix socket, enable server.unix_socket");
+        }
+
+        let pid = signal_daemon_reload()?;
+        println!("✓ Sent SIGHUP to cloud-netconfigd (pid {}), check its logs for the outcome", pid);
+        return Ok(());
+    }
+
+    let data = post_daemon(&format!("/api/reload?force={}", force))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to reach cloud-netconfigd: {}", e))?;
+
+    if data.get("status").and_then(|s| s.as_str()) != Some("ok") {
+        let message = data.get("message").and_then(|m| m.as_str()).unwrap_or("unknown error");
+        return Err(anyhow::anyhow!("Reload failed, daemon keeps its current configuration: {}", message));
+    }
+
+    let changes = data.get("changes").and_then(|c| c.as_array()).cloned().unwrap_or_default();
+    if changes.is_empty() {
+        println!("✓ Configuration reloaded, nothing changed");
+    } else {
+        println!("✓ Configuration reloaded, applied:");
+        for change in changes {
+            println!("  - {}", change.as_str().unwrap_or(""));
+        }
+    }
+
+    Ok(())
+}
+
+/// Extracts the diff and pause state from a control or plan response
+fn parse_diff_response(data: &serde_json::Value, action: &str) -> anyhow::Result<(provider::Diff, bool)> {
+    if data.get("status").and_then(|s| s.as_str()) != Some("ok") {
+        let message = data.get("message").and_then(|m| m.as_str()).unwrap_or("unknown error");
+        return Err(anyhow::anyhow!("{} failed: {}", action, message));
+    }
+
+    let diff: provider::Diff = serde_json::from_value(data.get("diff").cloned().unwrap_or_default())
+        .unwrap_or_default();
+    let paused = data.get("paused").and_then(|p| p.as_bool()).unwrap_or(false);
+
+    Ok((diff, paused))
+}
+
+async fn show_plan() -> anyhow::Result<()> {
+    let data = fetch_metadata("/api/plan")
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to reach cloud-netconfigd: {}", e))?;
+
+    let (diff, paused) = parse_diff_response(&data, "Plan")?;
+
+    if diff.is_empty() {
+        println!("✓ Network matches metadata, nothing to change");
+    } else {
+        println!("Changes the next configuration pass would make:");
+        for change in &diff.changes {
+            println!("  - {}", change);
+        }
+    }
+
+    if paused {
+        println!("Network management is paused, run 'cnctl resume' to apply changes");
+    }
+
+    Ok(())
+}
+
+/// Calls a control endpoint and prints the changes the daemon made
+async fn control_daemon(endpoint: &str, action: &str) -> anyhow::Result<()> {
+    let data = post_daemon(endpoint)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to reach cloud-netconfigd: {}", e))?;
+
+    let (diff, paused) = parse_diff_response(&data, action)?;
+
+    if diff.is_empty() {
+        println!("✓ {} done, nothing changed", action);
+    } else {
+        println!("✓ {} done, {}:", action, if paused { "pending while paused" } else { "applied" });
+        for change in &diff.changes {
+            println!("  - {}", change);
+        }
+    }
+
+    if paused {
+        println!("Network management is paused, run 'cnctl resume' to apply changes");
+    }
 
     Ok(())
 }
@@ -241,6 +479,26 @@ async fn main() -> anyhow::Result<()> {
             reload_daemon(*force).await?;
         }
 
+        Commands::Plan => {
+            show_plan().await?;
+        }
+
+        Commands::Refresh => {
+            control_daemon("/api/refresh", "Refresh").await?;
+        }
+
+        Commands::Link { action, name } => {
+            control_daemon(&format!("/api/links/{}/{}", name, action), &format!("Link {}", action)).await?;
+        }
+
+        Commands::Pause => {
+            control_daemon("/api/pause", "Pause").await?;
+        }
+
+        Commands::Resume => {
+            control_daemon("/api/resume", "Resume").await?;
+        }
+
         Commands::Version => {
             println!("cnctl version {}", conf::VERSION);
             println!("License: LGPL-3.0-or-later");
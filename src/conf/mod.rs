@@ -8,6 +8,7 @@ use tracing::Level;
 pub const VERSION: &str = "0.3.0";
 pub const CONF_FILE: &str = "cloud-network";
 pub const CONF_PATH: &str = "/etc/cloud-network";
+pub const RUNTIME_DIR: &str = "/run/cloud-network";
 
 pub const DEFAULT_HTTP_REQUEST_TIMEOUT: u64 = 10000;
 
@@ -38,6 +39,17 @@ pub struct LoggingConfig {
 pub struct ServerConfig {
     pub listen: ListenConfig,
     pub tls: Option<TlsConfig>,
+    pub unix_socket: UnixSocketConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UnixSocketConfig {
+    pub enabled: bool,
+    pub path: String,
+    /// Peers allowed to call mutating endpoints, matched by uid or gid
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -77,6 +89,7 @@ pub struct NetworkConfig {
     pub primary: PrimaryConfig,
     pub routing: RoutingConfig,
     pub mtu: MtuConfig,
+    pub on_shutdown: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -116,25 +129,63 @@ pub struct CloudConfig {
     pub azure: AzureCloudConfig,
     pub aws: AwsCloudConfig,
     pub gcp: GcpCloudConfig,
+    pub alibaba: AlibabaCloudConfig,
+    pub oracle: OracleCloudConfig,
+    pub digitalocean: DigitalOceanCloudConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct AzureCloudConfig {
+    pub endpoint: String,
     pub api_version: String,
+    pub scheduled_events: AzureScheduledEventsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AzureScheduledEventsConfig {
+    pub enabled: bool,
+    pub interval: String,
+    pub acknowledge: bool,
+    pub drain_hook: Option<String>,
+    pub drain_timeout: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct AwsCloudConfig {
+    pub endpoint: String,
     pub imds_version: u8,
     pub token_ttl: Option<u64>,
+    pub prefix_delegation: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct GcpCloudConfig {
+    pub endpoint: String,
     pub recursive: bool,
+    pub ip_aliases: String,
+    pub wait_for_change: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AlibabaCloudConfig {
+    pub endpoint: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OracleCloudConfig {
+    pub endpoint: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DigitalOceanCloudConfig {
+    pub endpoint: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -201,6 +252,18 @@ impl Default for ServerConfig {
         Self {
             listen: ListenConfig::default(),
             tls: None,
+            unix_socket: UnixSocketConfig::default(),
+        }
+    }
+}
+
+impl Default for UnixSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: format!("{}/cloud-network.sock", RUNTIME_DIR),
+            allowed_uids: vec![0],
+            allowed_gids: Vec::new(),
         }
     }
 }
@@ -241,6 +304,7 @@ impl Default for NetworkConfig {
             primary: PrimaryConfig::default(),
             routing: RoutingConfig::default(),
             mtu: MtuConfig::default(),
+            on_shutdown: "keep".to_string(),
         }
     }
 }
@@ -290,6 +354,9 @@ impl Default for CloudConfig {
             azure: AzureCloudConfig::default(),
             aws: AwsCloudConfig::default(),
             gcp: GcpCloudConfig::default(),
+            alibaba: AlibabaCloudConfig::default(),
+            oracle: OracleCloudConfig::default(),
+            digitalocean: DigitalOceanCloudConfig::default(),
         }
     }
 }
@@ -297,7 +364,21 @@ impl Default for CloudConfig {
 impl Default for AzureCloudConfig {
     fn default() -> Self {
         Self {
+            endpoint: "http://169.254.169.254/metadata".to_string(),
             api_version: "2021-02-01".to_string(),
+            scheduled_events: AzureScheduledEventsConfig::default(),
+        }
+    }
+}
+
+impl Default for AzureScheduledEventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: "10s".to_string(),
+            acknowledge: false,
+            drain_hook: None,
+            drain_timeout: "5m".to_string(),
         }
     }
 }
@@ -305,8 +386,10 @@ impl Default for AzureCloudConfig {
 impl Default for AwsCloudConfig {
     fn default() -> Self {
         Self {
+            endpoint: "http://169.254.169.254/latest".to_string(),
             imds_version: 1,
             token_ttl: None,
+            prefix_delegation: "none".to_string(),
         }
     }
 }
@@ -314,7 +397,34 @@ impl Default for AwsCloudConfig {
 impl Default for GcpCloudConfig {
     fn default() -> Self {
         Self {
+            endpoint: "http://metadata.google.internal/computeMetadata/v1".to_string(),
             recursive: true,
+            ip_aliases: "route".to_string(),
+            wait_for_change: true,
+        }
+    }
+}
+
+impl Default for AlibabaCloudConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://100.100.100.200/latest/meta-data".to_string(),
+        }
+    }
+}
+
+impl Default for OracleCloudConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://169.254.169.254/opc/v2".to_string(),
+        }
+    }
+}
+
+impl Default for DigitalOceanCloudConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://169.254.169.254/metadata".to_string(),
         }
     }
 }
@@ -362,15 +472,24 @@ impl Default for FeaturesConfig {
 // Implementation methods
 impl Config {
     pub fn parse() -> Result<Self> {
-        let config_path = std::path::Path::new(CONF_PATH).join(format!("{}.yaml", CONF_FILE));
+        let config_path = Self::path();
 
-        let config_content = match std::fs::read_to_string(&config_path) {
-            Ok(content) => content,
-            Err(_) => {
-                tracing::warn!("Failed to read config file, using defaults");
-                return Ok(Self::default());
-            }
-        };
+        if !config_path.exists() {
+            tracing::warn!("Failed to read config file, using defaults");
+            return Ok(Self::default());
+        }
+
+        Self::load()
+    }
+
+    /// Reads and validates the config file, failing instead of falling back
+    /// to defaults. Used on reload so a broken file never replaces a working
+    /// configuration.
+    pub fn load() -> Result<Self> {
+        let config_path = Self::path();
+
+        let config_content = std::fs::read_to_string(&config_path)
+            .with_context(|| format!("Failed to read config file '{}'", config_path.display()))?;
 
         let config: Config = serde_yaml::from_str(&config_content)
             .context("Failed to parse config file")?;
@@ -381,6 +500,10 @@ impl Config {
         Ok(config)
     }
 
+    pub fn path() -> std::path::PathBuf {
+        std::path::Path::new(CONF_PATH).join(format!("{}.yaml", CONF_FILE))
+    }
+
     fn validate(&self) -> Result<()> {
         // Validate refresh interval
         parse_duration(&self.metadata.refresh_interval)
@@ -390,15 +513,85 @@ impl Config {
         parse_duration(&self.metadata.request_timeout)
             .context("Invalid metadata request_timeout")?;
 
+        // Validate retry settings
+        parse_duration(&self.metadata.retry.backoff)
+            .context("Invalid metadata retry backoff")?;
+
+        if self.metadata.retry.enabled && self.metadata.retry.max_attempts == 0 {
+            return Err(anyhow::anyhow!("Invalid metadata retry max_attempts, expected at least 1"));
+        }
+
         // Validate watchdog interval
         parse_duration(&self.security.watchdog.interval)
             .context("Invalid watchdog interval")?;
 
+        // Validate Azure scheduled events intervals
+        parse_duration(&self.cloud.azure.scheduled_events.interval)
+            .context("Invalid azure scheduled_events interval")?;
+
+        parse_duration(&self.cloud.azure.scheduled_events.drain_timeout)
+            .context("Invalid azure scheduled_events drain_timeout")?;
+
         // Validate port
         if self.server.listen.port == 0 {
             return Err(anyhow::anyhow!("Invalid server port"));
         }
 
+        let socket_dir = std::path::Path::new(&self.server.unix_socket.path).parent();
+        if self.server.unix_socket.enabled && socket_dir != Some(std::path::Path::new(RUNTIME_DIR)) {
+            return Err(anyhow::anyhow!(
+                "Invalid server unix_socket path '{}', expected a file in {}",
+                self.server.unix_socket.path,
+                RUNTIME_DIR
+            ));
+        }
+
+        if !matches!(self.network.on_shutdown.as_str(), "keep" | "remove") {
+            return Err(anyhow::anyhow!(
+                "Invalid network on_shutdown '{}', expected keep or remove",
+                self.network.on_shutdown
+            ));
+        }
+
+        // Validate metadata service endpoints
+        for endpoint in [
+            &self.cloud.azure.endpoint,
+            &self.cloud.aws.endpoint,
+            &self.cloud.gcp.endpoint,
+            &self.cloud.alibaba.endpoint,
+            &self.cloud.oracle.endpoint,
+            &self.cloud.digitalocean.endpoint,
+        ] {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(anyhow::anyhow!("Invalid metadata endpoint '{}', expected an http(s) URL", endpoint));
+            }
+        }
+
+        // Validate EC2 metadata service settings
+        if !matches!(self.cloud.aws.imds_version, 1 | 2) {
+            return Err(anyhow::anyhow!("Invalid aws imds_version, expected 1 or 2"));
+        }
+
+        if let Some(ttl) = self.cloud.aws.token_ttl {
+            if ttl == 0 || ttl > 21600 {
+                return Err(anyhow::anyhow!("Invalid aws token_ttl, expected 1-21600 seconds"));
+            }
+        }
+
+        if !matches!(self.cloud.aws.prefix_delegation.as_str(), "none" | "route" | "address") {
+            return Err(anyhow::anyhow!(
+                "Invalid aws prefix_delegation '{}', expected none, route or address",
+                self.cloud.aws.prefix_delegation
+            ));
+        }
+
+        if !matches!(self.cloud.gcp.ip_aliases.as_str(), "route" | "address") {
+            return Err(anyhow::anyhow!(
+                "Invalid gcp ip_aliases '{}', expected route or address",
+                self.cloud.gcp.ip_aliases
+            ));
+        }
+
         Ok(())
     }
 
@@ -426,11 +619,26 @@ impl Config {
             .unwrap_or_else(|_| Duration::from_secs(10))
     }
 
+    pub fn get_retry_backoff(&self) -> Duration {
+        parse_duration(&self.metadata.retry.backoff)
+            .unwrap_or_else(|_| Duration::from_secs(5))
+    }
+
     pub fn get_watchdog_interval(&self) -> Duration {
         parse_duration(&self.security.watchdog.interval)
             .unwrap_or_else(|_| Duration::from_secs(30))
     }
 
+    pub fn get_scheduled_events_interval(&self) -> Duration {
+        parse_duration(&self.cloud.azure.scheduled_events.interval)
+            .unwrap_or_else(|_| Duration::from_secs(10))
+    }
+
+    pub fn get_drain_timeout(&self) -> Duration {
+        parse_duration(&self.cloud.azure.scheduled_events.drain_timeout)
+            .unwrap_or_else(|_| Duration::from_secs(300))
+    }
+
     pub fn get_listen_addr(&self) -> String {
         format!("{}:{}", self.server.listen.address, self.server.listen.port)
     }
@@ -478,4 +686,40 @@ mod tests {
         assert_eq!(config.server.listen.port, 5209);
         assert_eq!(config.network.routing.table_base, 9999);
     }
+
+    #[test]
+    fn test_validate_aws_imds() {
+        let mut config = Config::default();
+        config.cloud.aws.imds_version = 2;
+        config.cloud.aws.token_ttl = Some(300);
+        assert!(config.validate().is_ok());
+
+        config.cloud.aws.imds_version = 3;
+        assert!(config.validate().is_err());
+
+        config.cloud.aws.imds_version = 2;
+        config.cloud.aws.token_ttl = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_unix_socket() {
+        let mut config = Config::default();
+        config.server.unix_socket.enabled = true;
+        assert!(config.validate().is_ok());
+
+        config.server.unix_socket.path = "/tmp/cloud-network.sock".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_retry_attempts() {
+        let mut config = Config::default();
+        config.metadata.retry.max_attempts = 0;
+        assert!(config.validate().is_err());
+
+        // Ignored when retries are off
+        config.metadata.retry.enabled = false;
+        assert!(config.validate().is_ok());
+    }
 }
@@ -3,6 +3,7 @@
 
 pub mod cloud;
 pub mod conf;
+pub mod metrics;
 pub mod network;
 pub mod parser;
 pub mod provider;
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PREFIX: &str = "cloud_netconfig";
+
+/// Upper bounds in seconds, metadata requests are usually in the low
+/// milliseconds but retries with backoff push them to tens of seconds
+const DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Process wide metrics exported on /metrics
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; DURATION_BUCKETS.len()];
+        }
+
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+
+        for (i, bound) in DURATION_BUCKETS.iter().enumerate() {
+            let count = self.buckets.get(i).copied().unwrap_or(0);
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, count);
+        }
+
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, self.count);
+        let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces(labels), self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    metadata_attempts: BTreeMap<String, u64>,
+    metadata_failures: BTreeMap<String, u64>,
+    metadata_duration: BTreeMap<String, Histogram>,
+    reconcile_runs: BTreeMap<&'static str, u64>,
+    reconcile_duration: Histogram,
+    objects_added: BTreeMap<String, u64>,
+    objects_removed: BTreeMap<String, u64>,
+    managed_objects: BTreeMap<(String, &'static str), u64>,
+    last_success: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    state: Mutex<State>,
+}
+
+impl Metrics {
+    /// One HTTP request to the metadata service, retries count separately
+    pub fn metadata_request_attempt(&self, provider: &str) {
+        *self.state.lock().unwrap().metadata_attempts.entry(provider.to_string()).or_default() += 1;
+    }
+
+    /// One metadata fetch, including all its requests and retries
+    pub fn observe_metadata_fetch(&self, provider: &str, duration: Duration, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        if !ok {
+            *state.metadata_failures.entry(provider.to_string()).or_default() += 1;
+        }
+
+        state.metadata_duration.entry(provider.to_string()).or_default().observe(duration);
+    }
+
+    /// One configuration pass. `applied` is false when changes were only
+    /// computed (paused or dry-run), which does not count as a success.
+    pub fn observe_reconcile(&self, duration: Duration, ok: bool, applied: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        *state.reconcile_runs.entry(if ok { "success" } else { "failure" }).or_default() += 1;
+        state.reconcile_duration.observe(duration);
+
+        if ok && applied {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            state.last_success = Some(now.as_secs_f64());
+        }
+    }
+
+    /// Kind is one of address, route, local_route or rule
+    pub fn object_added(&self, kind: &str) {
+        *self.state.lock().unwrap().objects_added.entry(kind.to_string()).or_default() += 1;
+    }
+
+    pub fn object_removed(&self, kind: &str) {
+        *self.state.lock().unwrap().objects_removed.entry(kind.to_string()).or_default() += 1;
+    }
+
+    /// Replaces the managed object gauges with counts per link and kind
+    pub fn set_managed_objects(&self, counts: Vec<(String, &'static str, u64)>) {
+        let mut state = self.state.lock().unwrap();
+
+        state.managed_objects = counts
+            .into_iter()
+            .map(|(link, kind, count)| ((link, kind), count))
+            .collect();
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        header(&mut out, "metadata_fetch_attempts_total", "counter", "Metadata HTTP requests per provider, including retries");
+        for (provider, count) in &state.metadata_attempts {
+            let _ = writeln!(out, "{}_metadata_fetch_attempts_total{{provider=\"{}\"}} {}", PREFIX, provider, count);
+        }
+
+        header(&mut out, "metadata_fetch_failures_total", "counter", "Failed metadata fetches per provider");
+        for (provider, count) in &state.metadata_failures {
+            let _ = writeln!(out, "{}_metadata_fetch_failures_total{{provider=\"{}\"}} {}", PREFIX, provider, count);
+        }
+
+        header(&mut out, "metadata_fetch_duration_seconds", "histogram", "Metadata fetch latency including retries");
+        for (provider, histogram) in &state.metadata_duration {
+            histogram.render(
+                &mut out,
+                &format!("{}_metadata_fetch_duration_seconds", PREFIX),
+                &format!("provider=\"{}\"", provider),
+            );
+        }
+
+        header(&mut out, "reconcile_runs_total", "counter", "Network configuration passes by result");
+        for (result, count) in &state.reconcile_runs {
+            let _ = writeln!(out, "{}_reconcile_runs_total{{result=\"{}\"}} {}", PREFIX, result, count);
+        }
+
+        header(&mut out, "reconcile_duration_seconds", "histogram", "Network configuration pass duration");
+        state
+            .reconcile_duration
+            .render(&mut out, &format!("{}_reconcile_duration_seconds", PREFIX), "");
+
+        header(&mut out, "objects_added_total", "counter", "Addresses, routes and rules added");
+        for (kind, count) in &state.objects_added {
+            let _ = writeln!(out, "{}_objects_added_total{{kind=\"{}\"}} {}", PREFIX, kind, count);
+        }
+
+        header(&mut out, "objects_removed_total", "counter", "Addresses, routes and rules removed");
+        for (kind, count) in &state.objects_removed {
+            let _ = writeln!(out, "{}_objects_removed_total{{kind=\"{}\"}} {}", PREFIX, kind, count);
+        }
+
+        header(&mut out, "managed_objects", "gauge", "Objects currently managed per link");
+        for ((link, kind), count) in &state.managed_objects {
+            let _ = writeln!(out, "{}_managed_objects{{link=\"{}\",kind=\"{}\"}} {}", PREFIX, link, kind, count);
+        }
+
+        header(
+            &mut out,
+            "last_success_timestamp_seconds",
+            "gauge",
+            "Unix time of the last successful configuration pass",
+        );
+        let _ = writeln!(
+            out,
+            "{}_last_success_timestamp_seconds {}",
+            PREFIX,
+            state.last_success.unwrap_or(0.0)
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+
+        metrics.metadata_request_attempt("aws");
+        metrics.metadata_request_attempt("aws");
+        metrics.observe_metadata_fetch("aws", Duration::from_millis(20), true);
+        metrics.observe_metadata_fetch("aws", Duration::from_secs(2), false);
+        metrics.observe_reconcile(Duration::from_millis(300), true, true);
+        metrics.object_added("address");
+        metrics.object_added("address");
+        metrics.object_removed("rule");
+        metrics.set_managed_objects(vec![("eth1".to_string(), "address", 2)]);
+
+        let text = metrics.render();
+
+        assert!(text.contains("cloud_netconfig_metadata_fetch_attempts_total{provider=\"aws\"} 2"));
+        assert!(text.contains("cloud_netconfig_metadata_fetch_failures_total{provider=\"aws\"} 1"));
+        assert!(text.contains("cloud_netconfig_metadata_fetch_duration_seconds_bucket{provider=\"aws\",le=\"0.025\"} 1"));
+        assert!(text.contains("cloud_netconfig_metadata_fetch_duration_seconds_bucket{provider=\"aws\",le=\"+Inf\"} 2"));
+        assert!(text.contains("cloud_netconfig_reconcile_duration_seconds_bucket{le=\"0.5\"} 1"));
+        assert!(text.contains("cloud_netconfig_reconcile_duration_seconds_count 1"));
+        assert!(text.contains("cloud_netconfig_reconcile_runs_total{result=\"success\"} 1"));
+        assert!(text.contains("cloud_netconfig_objects_added_total{kind=\"address\"} 2"));
+        assert!(text.contains("cloud_netconfig_objects_removed_total{kind=\"rule\"} 1"));
+        assert!(text.contains("cloud_netconfig_managed_objects{link=\"eth1\",kind=\"address\"} 2"));
+        assert!(!text.contains("cloud_netconfig_last_success_timestamp_seconds 0\n"));
+    }
+
+    #[test]
+    fn test_paused_pass_is_not_a_success() {
+        let metrics = Metrics::default();
+
+        metrics.observe_reconcile(Duration::from_millis(10), true, false);
+
+        let text = metrics.render();
+        assert!(text.contains("cloud_netconfig_reconcile_runs_total{result=\"success\"} 1"));
+        assert!(text.contains("cloud_netconfig_last_success_timestamp_seconds 0\n"));
+    }
+}
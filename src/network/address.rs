@@ -1,15 +1,14 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use super::NetlinkContext;
 use anyhow::{Context, Result};
 use futures::stream::TryStreamExt;
-use rtnetlink::new_connection;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr};
 
-pub async fn address_add(if_index: u32, address: &str) -> Result<()> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn address_add(nl: &NetlinkContext, if_index: u32, address: &str) -> Result<()> {
+    let handle = nl.handle()?;
 
     // Parse the CIDR notation
     let parts: Vec<&str> = address.split('/').collect();
@@ -41,8 +40,8 @@ pub async fn address_add(if_index: u32, address: &str) -> Result<()> {
     }
 }
 
-pub async fn address_set(name: &str, address: &str) -> Result<()> {
-    let if_index = super::get_link_index_by_name(name).await?;
+pub async fn address_set(nl: &NetlinkContext, name: &str, address: &str) -> Result<()> {
+    let if_index = super::get_link_index_by_name(nl, name).await?;
 
     // Parse the CIDR notation
     let parts: Vec<&str> = address.split('/').collect();
@@ -53,8 +52,7 @@ pub async fn address_set(name: &str, address: &str) -> Result<()> {
     let ip: IpAddr = parts[0].parse()?;
     let prefix_len: u8 = parts[1].parse()?;
 
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+    let handle = nl.handle()?;
 
     // Set replaces existing address
     handle
@@ -68,11 +66,10 @@ pub async fn address_set(name: &str, address: &str) -> Result<()> {
     Ok(())
 }
 
-pub async fn get_ipv4_addresses(if_name: &str) -> Result<HashMap<String, bool>> {
-    let if_index = super::get_link_index_by_name(if_name).await?;
+pub async fn get_ipv4_addresses(nl: &NetlinkContext, if_name: &str) -> Result<HashMap<String, bool>> {
+    let if_index = super::get_link_index_by_name(nl, if_name).await?;
 
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+    let handle = nl.handle()?;
 
     let mut addresses = HashMap::new();
     let mut addr_stream = handle.address().get().set_link_index_filter(if_index).execute();
@@ -107,8 +104,43 @@ pub async fn get_ipv4_addresses(if_name: &str) -> Result<HashMap<String, bool>>
     Ok(addresses)
 }
 
-pub async fn address_remove(if_name: &str, address: &str) -> Result<()> {
-    let if_index = super::get_link_index_by_name(if_name).await?;
+pub async fn get_ipv6_addresses(nl: &NetlinkContext, if_name: &str) -> Result<HashMap<String, bool>> {
+    let if_index = super::get_link_index_by_name(nl, if_name).await?;
+
+    let handle = nl.handle()?;
+
+    let mut addresses = HashMap::new();
+    let mut addr_stream = handle.address().get().set_link_index_filter(if_index).execute();
+
+    while let Some(addr_msg) = addr_stream.try_next().await? {
+        // Check if it's IPv6
+        if addr_msg.header.family == 10 {
+            // AF_INET6 = 10
+            let ip = addr_msg
+                .nlas
+                .iter()
+                .find_map(|nla| {
+                    if let netlink_packet_route::address::nlas::Nla::Address(addr) = nla {
+                        let octets: [u8; 16] = addr.as_slice().try_into().ok()?;
+                        Some(Ipv6Addr::from(octets).to_string())
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or_default();
+
+            if !ip.is_empty() {
+                let cidr = format!("{}/{}", ip, addr_msg.header.prefix_len);
+                addresses.insert(cidr, true);
+            }
+        }
+    }
+
+    Ok(addresses)
+}
+
+pub async fn address_remove(nl: &NetlinkContext, if_name: &str, address: &str) -> Result<()> {
+    let if_index = super::get_link_index_by_name(nl, if_name).await?;
 
     // Parse the CIDR notation
     let parts: Vec<&str> = address.split('/').collect();
@@ -119,8 +151,7 @@ pub async fn address_remove(if_name: &str, address: &str) -> Result<()> {
     let ip: IpAddr = parts[0].parse()?;
     let prefix_len: u8 = parts[1].parse()?;
 
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+    let handle = nl.handle()?;
 
     // Silently ignore errors
     handle
@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use super::Links;
+use anyhow::Result;
+use rtnetlink::{new_connection, Handle};
+use std::sync::{Arc, Mutex};
+
+/// Shared rtnetlink connection. The connection is opened on first use and
+/// reused by every request; clones share the same connection and link cache.
+#[derive(Clone, Default)]
+pub struct NetlinkContext {
+    handle: Arc<Mutex<Option<Handle>>>,
+    links: Arc<Mutex<Option<Links>>>,
+}
+
+impl NetlinkContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle(&self) -> Result<Handle> {
+        let mut handle = self.handle.lock().unwrap();
+        if let Some(ref handle) = *handle {
+            return Ok(handle.clone());
+        }
+
+        let (connection, new_handle, _) = new_connection()?;
+        tokio::spawn(connection);
+
+        *handle = Some(new_handle.clone());
+        Ok(new_handle)
+    }
+
+    /// Links as of the last dump, taken at the start of every configuration
+    /// pass by acquire_links. Dumps again once invalidated.
+    pub async fn links(&self) -> Result<Links> {
+        if let Some(links) = self.links.lock().unwrap().clone() {
+            return Ok(links);
+        }
+
+        super::acquire_links(self).await
+    }
+
+    pub(super) fn cache_links(&self, links: &Links) {
+        *self.links.lock().unwrap() = Some(links.clone());
+    }
+
+    /// Drops the cached dump, called when a pass ends and when links come
+    /// and go so lookups in between never see a stale index
+    pub fn invalidate_links(&self) {
+        *self.links.lock().unwrap() = None;
+    }
+}
+
+impl std::fmt::Debug for NetlinkContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NetlinkContext")
+            .field("connected", &self.handle.lock().unwrap().is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Link;
+
+    #[tokio::test]
+    async fn test_links_cache_shared_between_clones() {
+        let nl = NetlinkContext::new();
+        let mut links = Links::new();
+        links.insert(Link {
+            name: "eth0".to_string(),
+            ifindex: 2,
+            oper_state: "Up".to_string(),
+            mac: "02:00:00:00:00:01".to_string(),
+            mtu: 1500,
+            addresses: None,
+            driver: "ena".to_string(),
+            kind: None,
+            master: None,
+        });
+
+        nl.cache_links(&links);
+
+        let cached = nl.clone().links().await.unwrap();
+        assert_eq!(cached.links_by_mac.len(), 1);
+        assert_eq!(super::super::get_link_index_by_name(&nl, "eth0").await.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_invalidate_links() {
+        let nl = NetlinkContext::new();
+        nl.cache_links(&Links::new());
+        assert!(nl.links.lock().unwrap().is_some());
+
+        nl.clone().invalidate_links();
+        assert!(nl.links.lock().unwrap().is_none());
+    }
+}
@@ -1,11 +1,15 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use super::NetlinkContext;
 use anyhow::{anyhow, Context, Result};
 use futures::stream::TryStreamExt;
-use rtnetlink::{new_connection, Handle};
 use std::collections::HashMap;
 
+// Driver of the Hyper-V synthetic NIC, the link to configure when an SR-IOV
+// VF shares its MAC
+const HV_NETVSC_DRIVER: &str = "hv_netvsc";
+
 #[derive(Debug, Clone)]
 pub struct Link {
     pub name: String,
@@ -14,24 +18,81 @@ pub struct Link {
     pub mac: String,
     pub mtu: u32,
     pub addresses: Option<HashMap<String, bool>>,
+    /// Kernel driver bound to the device, e.g. hv_netvsc or mlx5_core
+    pub driver: String,
+    /// IFLA_LINKINFO kind for virtual links (bond, vlan, ...)
+    pub kind: Option<String>,
+    /// Ifindex of the master when the link is enslaved (IFLA_MASTER)
+    pub master: Option<u32>,
+}
+
+impl Link {
+    pub fn is_enslaved(&self) -> bool {
+        self.master.is_some()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Links {
     pub links_by_mac: HashMap<String, Link>,
+    /// Links enslaved to another one (IFLA_MASTER), such as the SR-IOV VF
+    /// behind an Azure accelerated networking NIC
+    pub enslaved_by_index: HashMap<u32, Link>,
 }
 
 impl Links {
     pub fn new() -> Self {
         Self {
             links_by_mac: HashMap::new(),
+            enslaved_by_index: HashMap::new(),
         }
     }
+
+    /// Inserts a link keyed by MAC. Enslaved links are kept aside so they are
+    /// never configured, on Azure the VF shares its MAC with the synthetic NIC.
+    /// A VF not enslaved yet is kept aside the same way, whichever of the two
+    /// is inserted first.
+    pub fn insert(&mut self, link: Link) {
+        if link.is_enslaved() {
+            self.enslaved_by_index.insert(link.ifindex, link);
+            return;
+        }
+
+        match self.links_by_mac.get(&link.mac) {
+            Some(existing) if existing.driver == HV_NETVSC_DRIVER && link.driver != HV_NETVSC_DRIVER => {
+                self.enslaved_by_index.insert(link.ifindex, link);
+            }
+            Some(existing) if existing.driver != HV_NETVSC_DRIVER && link.driver == HV_NETVSC_DRIVER => {
+                if let Some(vf) = self.links_by_mac.insert(link.mac.clone(), link) {
+                    self.enslaved_by_index.insert(vf.ifindex, vf);
+                }
+            }
+            _ => {
+                self.links_by_mac.insert(link.mac.clone(), link);
+            }
+        }
+    }
+
+    pub fn is_enslaved(&self, if_index: u32) -> bool {
+        self.enslaved_by_index.contains_key(&if_index)
+    }
+}
+
+impl Default for Links {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn link_driver(name: &str) -> String {
+    std::fs::read_link(format!("/sys/class/net/{}/device/driver", name))
+        .ok()
+        .and_then(|path| path.file_name().map(|f| f.to_string_lossy().to_string()))
+        .unwrap_or_default()
 }
 
-pub async fn acquire_links() -> Result<Links> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn acquire_links(nl: &NetlinkContext) -> Result<Links> {
+    let handle = nl.handle()?;
 
     let mut links = Links::new();
     let mut link_stream = handle.link().get().execute();
@@ -93,18 +154,57 @@ pub async fn acquire_links() -> Result<Links> {
             })
             .unwrap_or_else(|| "unknown".to_string());
 
+        let master = link_msg.nlas.iter().find_map(|nla| {
+            if let netlink_packet_route::link::nlas::Nla::Master(index) = nla {
+                Some(*index)
+            } else {
+                None
+            }
+        });
+
+        let kind = link_msg.nlas.iter().find_map(|nla| {
+            if let netlink_packet_route::link::nlas::Nla::Info(infos) = nla {
+                infos.iter().find_map(|info| {
+                    if let netlink_packet_route::link::nlas::Info::Kind(kind) = info {
+                        Some(kind.to_string())
+                    } else {
+                        None
+                    }
+                })
+            } else {
+                None
+            }
+        });
+
+        let driver = link_driver(&name);
+
         let link = Link {
             name,
             ifindex: link_msg.header.index,
             oper_state,
-            mac: mac.clone(),
+            mac,
             mtu,
             addresses: None,
+            driver,
+            kind,
+            master,
         };
 
-        links.links_by_mac.insert(mac, link);
+        if link.is_enslaved() {
+            tracing::debug!(
+                "Link='{}' ifindex='{}' driver='{}' is enslaved to ifindex='{}'",
+                link.name,
+                link.ifindex,
+                link.driver,
+                link.master.unwrap_or_default()
+            );
+        }
+
+        links.insert(link);
     }
 
+    nl.cache_links(&links);
+
     Ok(links)
 }
 
@@ -117,9 +217,12 @@ pub async fn get_link_mac_by_index(links: &Links, if_index: u32) -> Result<Strin
         .ok_or_else(|| anyhow!("not found"))
 }
 
-pub async fn get_link_name_by_index(if_index: u32) -> Result<String> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn get_link_name_by_index(nl: &NetlinkContext, if_index: u32) -> Result<String> {
+    if let Some(link) = nl.links().await?.links_by_mac.values().find(|link| link.ifindex == if_index) {
+        return Ok(link.name.clone());
+    }
+
+    let handle = nl.handle()?;
 
     let mut link_stream = handle.link().get().match_index(if_index).execute();
 
@@ -142,9 +245,12 @@ pub async fn get_link_name_by_index(if_index: u32) -> Result<String> {
     Err(anyhow!("Link with index {} not found", if_index))
 }
 
-pub async fn get_link_index_by_name(name: &str) -> Result<u32> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn get_link_index_by_name(nl: &NetlinkContext, name: &str) -> Result<u32> {
+    if let Some(link) = nl.links().await?.links_by_mac.values().find(|link| link.name == name) {
+        return Ok(link.ifindex);
+    }
+
+    let handle = nl.handle()?;
 
     let mut link_stream = handle.link().get().match_name(name.to_string()).execute();
 
@@ -155,9 +261,8 @@ pub async fn get_link_index_by_name(name: &str) -> Result<u32> {
     Err(anyhow!("Link '{}' not found", name))
 }
 
-pub async fn link_set_oper_state_up(if_index: u32) -> Result<()> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn link_set_oper_state_up(nl: &NetlinkContext, if_index: u32) -> Result<()> {
+    let handle = nl.handle()?;
 
     handle
         .link()
@@ -170,9 +275,8 @@ pub async fn link_set_oper_state_up(if_index: u32) -> Result<()> {
     Ok(())
 }
 
-pub async fn link_set_mtu(if_index: u32, mtu: u32) -> Result<()> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn link_set_mtu(nl: &NetlinkContext, if_index: u32, mtu: u32) -> Result<()> {
+    let handle = nl.handle()?;
 
     handle
         .link()
@@ -184,3 +288,52 @@ pub async fn link_set_mtu(if_index: u32, mtu: u32) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(name: &str, ifindex: u32, driver: &str, master: Option<u32>) -> Link {
+        Link {
+            name: name.to_string(),
+            ifindex,
+            oper_state: "Up".to_string(),
+            mac: "00:0d:3a:5d:2d:66".to_string(),
+            mtu: 1500,
+            addresses: None,
+            driver: driver.to_string(),
+            kind: None,
+            master,
+        }
+    }
+
+    #[test]
+    fn test_links_insert_skips_enslaved_vf() {
+        let mut links = Links::new();
+        links.insert(link("eth0", 2, "hv_netvsc", None));
+        links.insert(link("enP1s1", 3, "mlx5_core", Some(2)));
+
+        assert_eq!(links.links_by_mac["00:0d:3a:5d:2d:66"].name, "eth0");
+        assert!(links.is_enslaved(3));
+        assert!(!links.is_enslaved(2));
+    }
+
+    #[test]
+    fn test_links_insert_prefers_netvsc_over_vf_not_enslaved_yet() {
+        // VF listed first, before hv_netvsc enslaves it
+        let mut links = Links::new();
+        links.insert(link("enP1s1", 3, "mlx5_core", None));
+        links.insert(link("eth0", 2, "hv_netvsc", None));
+
+        assert_eq!(links.links_by_mac["00:0d:3a:5d:2d:66"].name, "eth0");
+        assert!(links.is_enslaved(3));
+
+        // VF listed last
+        let mut links = Links::new();
+        links.insert(link("eth0", 2, "hv_netvsc", None));
+        links.insert(link("enP1s1", 3, "mlx5_core", None));
+
+        assert_eq!(links.links_by_mac["00:0d:3a:5d:2d:66"].name, "eth0");
+        assert!(links.is_enslaved(3));
+    }
+}
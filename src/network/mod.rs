@@ -2,11 +2,13 @@
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
 mod address;
+mod context;
 mod link;
 mod route;
 mod routing_policy_rule;
 
 pub use address::*;
+pub use context::*;
 pub use link::*;
 pub use route::*;
 pub use routing_policy_rule::*;
@@ -14,87 +16,59 @@ pub use routing_policy_rule::*;
 use anyhow::Result;
 use std::net::Ipv4Addr;
 
-pub async fn configure_by_index(if_index: u32) -> Result<()> {
-    let gw = get_ipv4_gateway(if_index).await?;
+/// Default route and policy rules of a supplementary link, which has no
+/// metadata and is configured from what the kernel has. Routes and rules go
+/// to `table`.
+pub async fn supplementary_link_objects(nl: &NetlinkContext, if_index: u32, table: u32) -> Result<(Route, Vec<RoutingPolicyRule>)> {
+    let gw = get_ipv4_gateway(nl, if_index).await?;
 
     let route = Route {
-        table: (ROUTE_TABLE_BASE + if_index + if_index) as u32,
+        table,
         if_index,
         gw: gw.clone(),
     };
 
-    route_add(&route).await?;
-
-    let link_name = get_link_name_by_index(if_index).await?;
-    let addresses = get_ipv4_addresses(&link_name).await?;
+    let link_name = get_link_name_by_index(nl, if_index).await?;
+    let addresses = get_ipv4_addresses(nl, &link_name).await?;
 
+    let mut rules = Vec::new();
     for addr in addresses.keys() {
         // Extract IP without prefix
         let ip_str = addr.split('/').next().unwrap_or(addr);
 
-        let from = RoutingPolicyRule {
-            from: Some(ip_str.to_string()),
+        rules.push(RoutingPolicyRule {
+            from: Some(rule_selector(ip_str)),
             to: None,
-            table: (ROUTE_TABLE_BASE + if_index) as u32,
-        };
-
-        routing_policy_rule_add(&from).await?;
+            table,
+        });
 
-        let to = RoutingPolicyRule {
+        rules.push(RoutingPolicyRule {
             from: None,
-            to: Some(ip_str.to_string()),
-            table: (ROUTE_TABLE_BASE + if_index) as u32,
-        };
-
-        routing_policy_rule_add(&to).await?;
-    }
-
-    Ok(())
-}
-
-pub async fn configure_supplementary_links(supplementary: &str) -> Result<()> {
-    let words: Vec<&str> = supplementary.split_whitespace().collect();
-
-    if words.is_empty() {
-        return Ok(());
-    }
-
-    for name in words {
-        match get_link_index_by_name(name).await {
-            Ok(index) => {
-                if let Err(e) = configure_by_index(index).await {
-                    tracing::error!(
-                        "Failed to configure network for link='{}' ifindex='{}': {}",
-                        name, index, e
-                    );
-                    return Err(e);
-                }
-                tracing::debug!(
-                    "Successfully configured network for link='{}' ifindex='{}'",
-                    name, index
-                );
-            }
-            Err(e) => {
-                tracing::debug!("Failed to find link='{}'. Ignoring...: {}", name, e);
-                continue;
-            }
-        }
+            to: Some(rule_selector(ip_str)),
+            table,
+        });
     }
 
-    Ok(())
+    Ok((route, rules))
 }
 
-pub async fn get_ipv4_gateway(if_index: u32) -> Result<String> {
+pub async fn get_ipv4_gateway(nl: &NetlinkContext, if_index: u32) -> Result<String> {
     // Try to get default gateway by link
-    if let Ok(gw) = get_default_ipv4_gateway_by_link(if_index).await {
+    if let Ok(gw) = get_default_ipv4_gateway_by_link(nl, if_index).await {
         return Ok(gw);
     }
 
     // Try to get any gateway by link
-    if let Ok(gw) = get_ipv4_gateway_by_link(if_index).await {
+    if let Ok(gw) = get_ipv4_gateway_by_link(nl, if_index).await {
         return Ok(gw);
     }
 
     // Fall back to system default gateway
-    get_default_ipv4_gateway().await
+    get_default_ipv4_gateway(nl).await
+}
+
+pub async fn get_ipv6_gateway(nl: &NetlinkContext, if_index: u32) -> Result<String> {
+    // IPv6 gateways are learned from router advertisements, so only the
+    // link's own default route is meaningful here
+    get_default_ipv6_gateway_by_link(nl, if_index).await
 }
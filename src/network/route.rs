@@ -1,21 +1,35 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use super::NetlinkContext;
 use anyhow::{anyhow, Result};
 use futures::stream::TryStreamExt;
-use rtnetlink::new_connection;
-use std::net::Ipv4Addr;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv6Addr};
 
-#[derive(Debug, Clone)]
+/// Kernel "local" routing table
+pub const RT_TABLE_LOCAL: u32 = 255;
+
+/// Protocol used to tag the local routes we install, same as google-guest-agent
+pub const RTPROT_CLOUD_NETCONFIG: u8 = 66;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Route {
     pub table: u32,
     pub if_index: u32,
     pub gw: String,
 }
 
-pub async fn get_default_ipv4_gateway() -> Result<String> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+/// Route of type local in the local table, makes the host accept traffic
+/// for a whole prefix without assigning every address
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocalRoute {
+    pub destination: String,
+    pub if_index: u32,
+}
+
+pub async fn get_default_ipv4_gateway(nl: &NetlinkContext) -> Result<String> {
+    let handle = nl.handle()?;
 
     let mut route_stream = handle.route().get(rtnetlink::IpVersion::V4).execute();
 
@@ -43,9 +57,8 @@ pub async fn get_default_ipv4_gateway() -> Result<String> {
     Err(anyhow!("Default gateway not found"))
 }
 
-pub async fn get_default_ipv4_gateway_by_link(if_index: u32) -> Result<String> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn get_default_ipv4_gateway_by_link(nl: &NetlinkContext, if_index: u32) -> Result<String> {
+    let handle = nl.handle()?;
 
     let mut route_stream = handle.route().get(rtnetlink::IpVersion::V4).execute();
 
@@ -81,9 +94,8 @@ pub async fn get_default_ipv4_gateway_by_link(if_index: u32) -> Result<String> {
     Err(anyhow!("Default gateway not found for link {}", if_index))
 }
 
-pub async fn get_ipv4_gateway_by_link(if_index: u32) -> Result<String> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn get_ipv4_gateway_by_link(nl: &NetlinkContext, if_index: u32) -> Result<String> {
+    let handle = nl.handle()?;
 
     let mut route_stream = handle.route().get(rtnetlink::IpVersion::V4).execute();
 
@@ -116,21 +128,169 @@ pub async fn get_ipv4_gateway_by_link(if_index: u32) -> Result<String> {
     Err(anyhow!("Gateway not found for link {}", if_index))
 }
 
-pub async fn route_add(route: &Route) -> Result<()> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn get_default_ipv6_gateway_by_link(nl: &NetlinkContext, if_index: u32) -> Result<String> {
+    let handle = nl.handle()?;
+
+    let mut route_stream = handle.route().get(rtnetlink::IpVersion::V6).execute();
 
-    let gw: Ipv4Addr = route.gw.parse()?;
+    while let Some(route_msg) = route_stream.try_next().await? {
+        // Check if this is a default route (::/0) for the specific link
+        let is_default = route_msg.header.destination_prefix_length == 0;
 
-    let result = handle
-        .route()
-        .add()
-        .v4()
-        .gateway(gw)
-        .output_interface(route.if_index)
-        .table(route.table)
-        .execute()
-        .await;
+        let matches_link = route_msg.nlas.iter().any(|nla| {
+            if let netlink_packet_route::route::nlas::Nla::Oif(index) = nla {
+                *index == if_index
+            } else {
+                false
+            }
+        });
+
+        if is_default && matches_link {
+            if let Some(gw) = route_msg.nlas.iter().find_map(|nla| {
+                if let netlink_packet_route::route::nlas::Nla::Gateway(addr) = nla {
+                    let octets: [u8; 16] = addr.as_slice().try_into().ok()?;
+                    Some(Ipv6Addr::from(octets).to_string())
+                } else {
+                    None
+                }
+            }) {
+                return Ok(gw);
+            }
+        }
+    }
+
+    Err(anyhow!("IPv6 default gateway not found for link {}", if_index))
+}
+
+/// Dumps the default routes (IPv4 and IPv6) of a routing table
+pub async fn get_routes_by_table(nl: &NetlinkContext, table: u32) -> Result<Vec<Route>> {
+    let handle = nl.handle()?;
+
+    let mut routes = Vec::new();
+
+    for version in [rtnetlink::IpVersion::V4, rtnetlink::IpVersion::V6] {
+        let mut route_stream = handle.route().get(version).execute();
+
+        while let Some(route_msg) = route_stream.try_next().await? {
+            if route_table_id(&route_msg) != table || route_msg.header.destination_prefix_length != 0 {
+                continue;
+            }
+
+            let mut gw = None;
+            let mut if_index = 0;
+
+            for nla in &route_msg.nlas {
+                match nla {
+                    netlink_packet_route::route::nlas::Nla::Gateway(addr) => gw = format_ip(addr),
+                    netlink_packet_route::route::nlas::Nla::Oif(index) => if_index = *index,
+                    _ => {}
+                }
+            }
+
+            if let Some(gw) = gw {
+                routes.push(Route { table, if_index, gw });
+            }
+        }
+    }
+
+    Ok(routes)
+}
+
+/// Dumps the local routes we installed (tagged with our protocol) on a link
+pub async fn get_local_routes(nl: &NetlinkContext, if_index: u32) -> Result<Vec<LocalRoute>> {
+    let handle = nl.handle()?;
+
+    let mut routes = Vec::new();
+
+    for version in [rtnetlink::IpVersion::V4, rtnetlink::IpVersion::V6] {
+        let mut route_stream = handle.route().get(version).execute();
+
+        while let Some(route_msg) = route_stream.try_next().await? {
+            if route_table_id(&route_msg) != RT_TABLE_LOCAL || route_msg.header.protocol != RTPROT_CLOUD_NETCONFIG {
+                continue;
+            }
+
+            let mut destination = None;
+            let mut oif = 0;
+
+            for nla in &route_msg.nlas {
+                match nla {
+                    netlink_packet_route::route::nlas::Nla::Destination(addr) => destination = format_ip(addr),
+                    netlink_packet_route::route::nlas::Nla::Oif(index) => oif = *index,
+                    _ => {}
+                }
+            }
+
+            if let (Some(destination), true) = (destination, oif == if_index) {
+                routes.push(LocalRoute {
+                    destination: format!("{}/{}", destination, route_msg.header.destination_prefix_length),
+                    if_index,
+                });
+            }
+        }
+    }
+
+    Ok(routes)
+}
+
+/// Tables above 255 are only carried in the RTA_TABLE attribute
+pub fn route_table_id(route_msg: &netlink_packet_route::RouteMessage) -> u32 {
+    route_msg
+        .nlas
+        .iter()
+        .find_map(|nla| {
+            if let netlink_packet_route::route::nlas::Nla::Table(table) = nla {
+                Some(*table)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(route_msg.header.table as u32)
+}
+
+fn format_ip(addr: &[u8]) -> Option<String> {
+    match addr.len() {
+        4 => {
+            let octets: [u8; 4] = addr.try_into().ok()?;
+            Some(std::net::Ipv4Addr::from(octets).to_string())
+        }
+        16 => {
+            let octets: [u8; 16] = addr.try_into().ok()?;
+            Some(Ipv6Addr::from(octets).to_string())
+        }
+        _ => None,
+    }
+}
+
+pub async fn route_add(nl: &NetlinkContext, route: &Route) -> Result<()> {
+    let handle = nl.handle()?;
+
+    let gw: IpAddr = route.gw.parse()?;
+
+    let result = match gw {
+        IpAddr::V4(gw) => {
+            handle
+                .route()
+                .add()
+                .v4()
+                .gateway(gw)
+                .output_interface(route.if_index)
+                .table(route.table)
+                .execute()
+                .await
+        }
+        IpAddr::V6(gw) => {
+            handle
+                .route()
+                .add()
+                .v6()
+                .gateway(gw)
+                .output_interface(route.if_index)
+                .table(route.table)
+                .execute()
+                .await
+        }
+    };
 
     match result {
         Ok(_) => Ok(()),
@@ -146,21 +306,120 @@ pub async fn route_add(route: &Route) -> Result<()> {
     }
 }
 
-pub async fn route_remove(route: &Route) -> Result<()> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
-
-    let gw: Ipv4Addr = route.gw.parse()?;
-
-    handle
-        .route()
-        .del()
-        .v4()
-        .gateway(gw)
-        .output_interface(route.if_index)
-        .table(route.table)
-        .execute()
-        .await?;
+pub async fn route_remove(nl: &NetlinkContext, route: &Route) -> Result<()> {
+    let handle = nl.handle()?;
+
+    let gw: IpAddr = route.gw.parse()?;
+
+    match gw {
+        IpAddr::V4(gw) => {
+            handle
+                .route()
+                .del()
+                .v4()
+                .gateway(gw)
+                .output_interface(route.if_index)
+                .table(route.table)
+                .execute()
+                .await?;
+        }
+        IpAddr::V6(gw) => {
+            handle
+                .route()
+                .del()
+                .v6()
+                .gateway(gw)
+                .output_interface(route.if_index)
+                .table(route.table)
+                .execute()
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn local_route_add(nl: &NetlinkContext, route: &LocalRoute) -> Result<()> {
+    let handle = nl.handle()?;
+
+    let (destination, prefix_len) = crate::parser::parse_cidr(&route.destination)?;
+
+    let result = match destination {
+        IpAddr::V4(destination) => {
+            handle
+                .route()
+                .add()
+                .v4()
+                .destination_prefix(destination, prefix_len)
+                .output_interface(route.if_index)
+                .kind(netlink_packet_route::constants::RTN_LOCAL)
+                .scope(netlink_packet_route::constants::RT_SCOPE_HOST)
+                .protocol(RTPROT_CLOUD_NETCONFIG)
+                .table(RT_TABLE_LOCAL)
+                .execute()
+                .await
+        }
+        IpAddr::V6(destination) => {
+            handle
+                .route()
+                .add()
+                .v6()
+                .destination_prefix(destination, prefix_len)
+                .output_interface(route.if_index)
+                .kind(netlink_packet_route::constants::RTN_LOCAL)
+                .scope(netlink_packet_route::constants::RT_SCOPE_HOST)
+                .protocol(RTPROT_CLOUD_NETCONFIG)
+                .table(RT_TABLE_LOCAL)
+                .execute()
+                .await
+        }
+    };
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            let err_str = format!("{}", e);
+            if err_str.contains("File exists") || err_str.contains("EEXIST") {
+                // Route already exists, this is okay
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("Failed to add local route: {}", e))
+            }
+        }
+    }
+}
+
+pub async fn local_route_remove(nl: &NetlinkContext, route: &LocalRoute) -> Result<()> {
+    let handle = nl.handle()?;
+
+    let (destination, prefix_len) = crate::parser::parse_cidr(&route.destination)?;
+
+    match destination {
+        IpAddr::V4(destination) => {
+            handle
+                .route()
+                .del()
+                .v4()
+                .destination_prefix(destination, prefix_len)
+                .output_interface(route.if_index)
+                .kind(netlink_packet_route::constants::RTN_LOCAL)
+                .table(RT_TABLE_LOCAL)
+                .execute()
+                .await?;
+        }
+        IpAddr::V6(destination) => {
+            handle
+                .route()
+                .del()
+                .v6()
+                .destination_prefix(destination, prefix_len)
+                .output_interface(route.if_index)
+                .kind(netlink_packet_route::constants::RTN_LOCAL)
+                .table(RT_TABLE_LOCAL)
+                .execute()
+                .await?;
+        }
+    }
 
     Ok(())
 }
@@ -1,45 +1,39 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use super::NetlinkContext;
 use anyhow::Result;
 use futures::stream::TryStreamExt;
-use rtnetlink::new_connection;
-use std::net::IpAddr;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RoutingPolicyRule {
     pub from: Option<String>,
     pub to: Option<String>,
     pub table: u32,
 }
 
-pub async fn routing_policy_rule_add(rule: &RoutingPolicyRule) -> Result<()> {
-    // Check if we have multiple interfaces
-    let links = super::acquire_links().await?;
-    if links.links_by_mac.len() < 2 {
-        return Ok(());
-    }
-
+pub async fn routing_policy_rule_add(nl: &NetlinkContext, rule: &RoutingPolicyRule) -> Result<()> {
     // Check if rule already exists
-    if rule_exists(rule).await? {
+    if rule_exists(nl, rule).await? {
         return Ok(());
     }
 
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+    let handle = nl.handle()?;
 
     let mut rule_request = handle.rule().add();
 
     // Set source address if specified
     if let Some(ref from) = rule.from {
-        let ip: IpAddr = from.parse()?;
-        rule_request = rule_request.source_prefix(ip, 32);
+        let (ip, prefix_len) = parse_rule_prefix(from)?;
+        rule_request = rule_request.source_prefix(ip, prefix_len);
     }
 
     // Set destination address if specified
     if let Some(ref to) = rule.to {
-        let ip: IpAddr = to.parse()?;
-        rule_request = rule_request.destination_prefix(ip, 32);
+        let (ip, prefix_len) = parse_rule_prefix(to)?;
+        rule_request = rule_request.destination_prefix(ip, prefix_len);
     }
 
     // Set table
@@ -61,22 +55,21 @@ pub async fn routing_policy_rule_add(rule: &RoutingPolicyRule) -> Result<()> {
     }
 }
 
-pub async fn routing_policy_rule_remove(rule: &RoutingPolicyRule) -> Result<()> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+pub async fn routing_policy_rule_remove(nl: &NetlinkContext, rule: &RoutingPolicyRule) -> Result<()> {
+    let handle = nl.handle()?;
 
     let mut rule_request = handle.rule().del();
 
     // Set source address if specified
     if let Some(ref from) = rule.from {
-        let ip: IpAddr = from.parse()?;
-        rule_request = rule_request.source_prefix(ip, 32);
+        let (ip, prefix_len) = parse_rule_prefix(from)?;
+        rule_request = rule_request.source_prefix(ip, prefix_len);
     }
 
     // Set destination address if specified
     if let Some(ref to) = rule.to {
-        let ip: IpAddr = to.parse()?;
-        rule_request = rule_request.destination_prefix(ip, 32);
+        let (ip, prefix_len) = parse_rule_prefix(to)?;
+        rule_request = rule_request.destination_prefix(ip, prefix_len);
     }
 
     // Set table
@@ -88,16 +81,61 @@ pub async fn routing_policy_rule_remove(rule: &RoutingPolicyRule) -> Result<()>
     Ok(())
 }
 
-async fn rule_exists(rule: &RoutingPolicyRule) -> Result<bool> {
-    let (connection, handle, _) = new_connection()?;
-    tokio::spawn(connection);
+/// Dumps the IPv4 and IPv6 policy rules pointing at a table
+pub async fn get_rules_by_table(nl: &NetlinkContext, table: u32) -> Result<Vec<RoutingPolicyRule>> {
+    let handle = nl.handle()?;
+
+    let mut rules = Vec::new();
+
+    for version in [rtnetlink::IpVersion::V4, rtnetlink::IpVersion::V6] {
+        let mut rule_stream = handle.rule().get(version).execute();
+
+        while let Some(rule_msg) = rule_stream.try_next().await? {
+            let mut rule = RoutingPolicyRule {
+                from: None,
+                to: None,
+                table: rule_table_id(&rule_msg),
+            };
+
+            for nla in &rule_msg.nlas {
+                match nla {
+                    netlink_packet_route::rule::nlas::Nla::Source(addr) => {
+                        rule.from = format_rule_prefix(addr, rule_msg.header.src_len);
+                    }
+                    netlink_packet_route::rule::nlas::Nla::Destination(addr) => {
+                        rule.to = format_rule_prefix(addr, rule_msg.header.dst_len);
+                    }
+                    _ => {}
+                }
+            }
 
-    let mut rule_stream = handle.rule().get(rtnetlink::IpVersion::V4).execute();
+            if rule.table == table {
+                rules.push(rule);
+            }
+        }
+    }
+
+    Ok(rules)
+}
+
+/// Normalizes an address or prefix to the form the kernel reports rule
+/// selectors in, see format_rule_prefix
+pub fn rule_selector(s: &str) -> String {
+    match parse_rule_prefix(s) {
+        Ok((ip, prefix_len)) if prefix_len == host_prefix_len(&ip) => ip.to_string(),
+        Ok((ip, prefix_len)) => format!("{}/{}", ip, prefix_len),
+        Err(_) => s.to_string(),
+    }
+}
+
+async fn rule_exists(nl: &NetlinkContext, rule: &RoutingPolicyRule) -> Result<bool> {
+    let handle = nl.handle()?;
+
+    let mut rule_stream = handle.rule().get(rule_ip_version(rule)).execute();
 
     while let Some(rule_msg) = rule_stream.try_next().await? {
         // Check table
-        let table = rule_msg.header.table as u32;
-        if table != rule.table {
+        if rule_table_id(&rule_msg) != rule.table {
             continue;
         }
 
@@ -107,11 +145,11 @@ async fn rule_exists(rule: &RoutingPolicyRule) -> Result<bool> {
 
         for nla in &rule_msg.nlas {
             match nla {
-                netlink_packet_route::rule::nlas::Nla::Source(addr) if addr.len() == 4 => {
-                    src_ip = Some(format!("{}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3]));
+                netlink_packet_route::rule::nlas::Nla::Source(addr) => {
+                    src_ip = format_rule_prefix(addr, rule_msg.header.src_len);
                 }
-                netlink_packet_route::rule::nlas::Nla::Destination(addr) if addr.len() == 4 => {
-                    dst_ip = Some(format!("{}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3]));
+                netlink_packet_route::rule::nlas::Nla::Destination(addr) => {
+                    dst_ip = format_rule_prefix(addr, rule_msg.header.dst_len);
                 }
                 _ => {}
             }
@@ -137,3 +175,72 @@ async fn rule_exists(rule: &RoutingPolicyRule) -> Result<bool> {
 
     Ok(false)
 }
+
+/// Tables above 255 are only carried in the FRA_TABLE attribute
+pub fn rule_table_id(rule_msg: &netlink_packet_route::RuleMessage) -> u32 {
+    rule_msg
+        .nlas
+        .iter()
+        .find_map(|nla| {
+            if let netlink_packet_route::rule::nlas::Nla::Table(table) = nla {
+                Some(*table)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(rule_msg.header.table as u32)
+}
+
+/// Rule selectors are either a single address or a CIDR prefix
+fn parse_rule_prefix(s: &str) -> Result<(IpAddr, u8)> {
+    if s.contains('/') {
+        crate::parser::parse_cidr(s)
+    } else {
+        let ip: IpAddr = s.parse()?;
+        Ok((ip, host_prefix_len(&ip)))
+    }
+}
+
+fn host_prefix_len(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn rule_ip_version(rule: &RoutingPolicyRule) -> rtnetlink::IpVersion {
+    let is_ipv6 = rule
+        .from
+        .as_ref()
+        .or(rule.to.as_ref())
+        .map(|ip| ip.contains(':'))
+        .unwrap_or(false);
+
+    if is_ipv6 {
+        rtnetlink::IpVersion::V6
+    } else {
+        rtnetlink::IpVersion::V4
+    }
+}
+
+/// Formats a rule selector the same way it is stored in RoutingPolicyRule,
+/// host prefixes without length and everything else in CIDR notation
+fn format_rule_prefix(addr: &[u8], prefix_len: u8) -> Option<String> {
+    let ip = match addr.len() {
+        4 => {
+            let octets: [u8; 4] = addr.try_into().ok()?;
+            IpAddr::from(Ipv4Addr::from(octets))
+        }
+        16 => {
+            let octets: [u8; 16] = addr.try_into().ok()?;
+            IpAddr::from(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+
+    if prefix_len == host_prefix_len(&ip) {
+        Some(ip.to_string())
+    } else {
+        Some(format!("{}/{}", ip, prefix_len))
+    }
+}
@@ -1,7 +1,7 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use anyhow::{anyhow, Result};
 
 pub fn parse_ip(ip: &str) -> Result<IpAddr> {
@@ -33,6 +33,79 @@ pub fn parse_mac(s: &str) -> String {
     result
 }
 
+/// Converts a dotted subnet mask such as 255.255.255.0 into a prefix length
+pub fn parse_netmask(mask: &str) -> Result<u8> {
+    let mask = mask.trim().parse::<Ipv4Addr>()
+        .map_err(|_| anyhow!("invalid netmask"))?;
+
+    let bits = u32::from(mask);
+    if bits.leading_ones() + bits.trailing_zeros() != 32 {
+        return Err(anyhow!("invalid netmask"));
+    }
+
+    Ok(bits.leading_ones() as u8)
+}
+
+/// Splits CIDR notation such as 10.0.0.0/24 into address and prefix length
+pub fn parse_cidr(s: &str) -> Result<(IpAddr, u8)> {
+    let (ip, prefix) = s.trim().split_once('/')
+        .ok_or_else(|| anyhow!("invalid CIDR"))?;
+
+    let ip = parse_ip(ip)?;
+    let prefix = prefix.parse::<u8>()
+        .map_err(|_| anyhow!("invalid CIDR prefix"))?;
+
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    if prefix > max {
+        return Err(anyhow!("invalid CIDR prefix"));
+    }
+
+    Ok((ip, prefix))
+}
+
+/// Checks whether an IP address falls within a CIDR range
+pub fn cidr_contains(cidr: &str, ip: &str) -> bool {
+    let (network, prefix) = match parse_cidr(cidr) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+
+    let ip = match parse_ip(ip) {
+        Ok(ip) => ip,
+        Err(_) => return false,
+    };
+
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Expands a small IPv4 prefix such as 10.0.1.16/28 into its addresses
+pub fn expand_ipv4_prefix(prefix: &str) -> Result<Vec<Ipv4Addr>> {
+    let (network, prefix_len) = parse_cidr(prefix)?;
+    let network = match network {
+        IpAddr::V4(network) => u32::from(network),
+        IpAddr::V6(_) => return Err(anyhow!("not an IPv4 prefix")),
+    };
+
+    if prefix_len < 24 {
+        return Err(anyhow!("prefix too large to expand"));
+    }
+
+    let size = 1u32 << (32 - prefix_len as u32);
+    let network = network & !(size - 1);
+
+    Ok((0..size).map(|i| Ipv4Addr::from(network + i)).collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -42,6 +115,34 @@ mod tests {
         assert_eq!(parse_mac("001122334455"), "00:11:22:33:44:55");
     }
 
+    #[test]
+    fn test_parse_netmask() {
+        assert_eq!(parse_netmask("255.255.255.0").unwrap(), 24);
+        assert_eq!(parse_netmask("255.255.240.0").unwrap(), 20);
+        assert_eq!(parse_netmask("0.0.0.0").unwrap(), 0);
+        assert!(parse_netmask("255.0.255.0").is_err());
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        assert!(cidr_contains("10.0.0.0/24", "10.0.0.17"));
+        assert!(!cidr_contains("10.0.0.0/24", "10.0.1.17"));
+        assert!(cidr_contains("0.0.0.0/0", "192.168.1.1"));
+        assert!(cidr_contains("2600:1f14::/64", "2600:1f14::1"));
+        assert!(!cidr_contains("10.0.0.0/24", "2600:1f14::1"));
+    }
+
+    #[test]
+    fn test_expand_ipv4_prefix() {
+        let ips = expand_ipv4_prefix("10.0.1.16/28").unwrap();
+        assert_eq!(ips.len(), 16);
+        assert_eq!(ips[0].to_string(), "10.0.1.16");
+        assert_eq!(ips[15].to_string(), "10.0.1.31");
+
+        assert!(expand_ipv4_prefix("10.0.0.0/16").is_err());
+        assert!(expand_ipv4_prefix("2600:1f14::/80").is_err());
+    }
+
     #[test]
     fn test_parse_ip_port() {
         let (ip, port) = parse_ip_port("127.0.0.1:5209").unwrap();
@@ -0,0 +1,248 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use crate::network::Link;
+use crate::web::{HttpClient, MetadataClient};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct AlibabaSystem {
+    pub instance_id: String,
+    pub instance_type: String,
+    pub region_id: String,
+    pub zone_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlibabaMacData {
+    pub mac: String,
+    pub network_interface_id: String,
+    pub primary_ip_address: String,
+    pub private_ipv4s: Vec<String>,
+    pub netmask: String,
+    pub gateway: String,
+}
+
+pub struct Alibaba {
+    system: AlibabaSystem,
+    macs: HashMap<String, AlibabaMacData>,
+    client: MetadataClient,
+}
+
+impl Alibaba {
+    pub fn new(config: &crate::conf::AlibabaCloudConfig, client: HttpClient) -> Self {
+        Self {
+            system: AlibabaSystem::default(),
+            macs: HashMap::new(),
+            client: MetadataClient::new(client, &config.endpoint),
+        }
+    }
+
+    async fn fetch_metadata_simple(&self, path: &str) -> Result<String> {
+        self.client.get_text(path).await
+    }
+
+    /// Fetches a field the link can be configured without, failures are
+    /// logged and yield an empty value
+    async fn fetch_metadata_optional(&self, path: &str) -> String {
+        match self.fetch_metadata_simple(path).await {
+            Ok(text) => text,
+            Err(e) => {
+                tracing::warn!("Failed to fetch metadata '{}': {}", path, e);
+                String::new()
+            }
+        }
+    }
+
+    async fn fetch_mac_data(&self, mac: &str) -> Result<AlibabaMacData> {
+        let base = format!("network/interfaces/macs/{}", mac);
+
+        let primary_ip_address = self
+            .fetch_metadata_simple(&format!("{}/primary-ip-address", base))
+            .await
+            .context("Failed to fetch primary-ip-address")?;
+
+        let netmask = self
+            .fetch_metadata_simple(&format!("{}/netmask", base))
+            .await
+            .context("Failed to fetch netmask")?;
+
+        let private_ipv4s = parse_private_ipv4s(
+            &self
+                .fetch_metadata_optional(&format!("{}/private-ipv4s", base))
+                .await,
+        );
+
+        let gateway = self.fetch_metadata_optional(&format!("{}/gateway", base)).await;
+
+        let network_interface_id = self
+            .fetch_metadata_optional(&format!("{}/network-interface-id", base))
+            .await;
+
+        Ok(AlibabaMacData {
+            mac: mac.to_string(),
+            network_interface_id,
+            primary_ip_address,
+            private_ipv4s,
+            netmask,
+            gateway,
+        })
+    }
+
+    fn parse_ipv4_addresses_from_metadata(&self, mac_data: &AlibabaMacData) -> Result<HashMap<String, bool>> {
+        let mut result = HashMap::new();
+        let prefix = crate::parser::parse_netmask(&mac_data.netmask)
+            .with_context(|| format!("Invalid netmask '{}'", mac_data.netmask))?;
+
+        // Primary address is also listed in private-ipv4s, the map takes care of duplicates
+        for addr in std::iter::once(&mac_data.primary_ip_address).chain(&mac_data.private_ipv4s) {
+            let addr = addr.trim();
+            if !addr.is_empty() {
+                result.insert(format!("{}/{}", addr, prefix), true);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// private-ipv4s is served as a JSON array, e.g. ["172.16.0.10","172.16.0.11"]
+fn parse_private_ipv4s(text: &str) -> Vec<String> {
+    serde_json::from_str::<Vec<String>>(text).unwrap_or_else(|_| {
+        text.lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect()
+    })
+}
+
+#[async_trait::async_trait]
+impl super::CloudProvider for Alibaba {
+    async fn fetch_cloud_metadata(&mut self) -> Result<()> {
+        self.system = AlibabaSystem {
+            instance_id: self.fetch_metadata_optional("instance-id").await,
+            instance_type: self.fetch_metadata_optional("instance/instance-type").await,
+            region_id: self.fetch_metadata_optional("region-id").await,
+            zone_id: self.fetch_metadata_optional("zone-id").await,
+        };
+
+        // Fetch MACs
+        let macs_text = self.fetch_metadata_simple("network/interfaces/macs/").await?;
+        let macs: Vec<String> = macs_text
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.trim_end_matches('/').to_lowercase())
+            .collect();
+
+        // Fetch data for each MAC
+        self.macs.clear();
+        for mac in macs {
+            match self.fetch_mac_data(&mac).await {
+                Ok(mac_data) => {
+                    self.macs.insert(mac, mac_data);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to fetch metadata for mac='{}': {}", mac, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn configure_network_from_cloud_meta(&self, env: &mut super::Environment) -> Result<()> {
+        for mac in self.macs.keys() {
+            if let Some(link) = env.links.links_by_mac.get(mac).cloned() {
+                self.configure_link_from_cloud_meta(env, &link).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn configure_link_from_cloud_meta(&self, env: &mut super::Environment, link: &Link) -> Result<()> {
+        if let Some(mac_data) = self.macs.get(&link.mac) {
+            // A guessed prefix would install wrong rules, leave the link alone
+            let addresses = match self.parse_ipv4_addresses_from_metadata(mac_data) {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    tracing::warn!(
+                        "Skipping link='{}' ifindex='{}', unusable metadata: {:#}",
+                        link.name,
+                        link.ifindex,
+                        e
+                    );
+                    return Ok(());
+                }
+            };
+            let gateway = if mac_data.gateway.is_empty() {
+                None
+            } else {
+                Some(mac_data.gateway.clone())
+            };
+
+            if !addresses.is_empty() {
+                super::network::configure_network(env, link, addresses, HashMap::new(), gateway, None, None).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn save_cloud_metadata(&self) -> Result<()> {
+        let path = format!("{}/alibaba", crate::conf::SYSTEM_STATE_DIR);
+        crate::system::create_and_save_json(&path, &self.system)?;
+        Ok(())
+    }
+
+    async fn link_save_cloud_metadata(&self, env: &super::Environment) -> Result<()> {
+        for (mac, mac_data) in &self.macs {
+            if let Some(link) = env.links.links_by_mac.get(mac) {
+                let path = format!("{}/{}", crate::conf::LINK_STATE_DIR, link.name);
+                crate::system::create_and_save_json(&path, mac_data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn system_metadata(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&self.system).ok()
+    }
+
+    fn link_metadata(&self, link: &Link) -> Option<serde_json::Value> {
+        serde_json::to_value(self.macs.get(&link.mac)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_private_ipv4s() {
+        assert_eq!(
+            parse_private_ipv4s(r#"["172.16.0.10","172.16.0.11"]"#),
+            vec!["172.16.0.10", "172.16.0.11"]
+        );
+        assert_eq!(parse_private_ipv4s("172.16.0.10\n"), vec!["172.16.0.10"]);
+    }
+
+    #[test]
+    fn test_invalid_netmask_is_rejected() {
+        let mac_data = AlibabaMacData {
+            mac: "00:16:3e:00:00:01".to_string(),
+            network_interface_id: "eni-1".to_string(),
+            primary_ip_address: "172.16.0.10".to_string(),
+            private_ipv4s: vec!["172.16.0.10".to_string(), "172.16.0.11".to_string()],
+            netmask: "255.255.0.255".to_string(),
+            gateway: "172.16.0.253".to_string(),
+        };
+
+        let alibaba = Alibaba::new(&crate::conf::AlibabaCloudConfig::default(), HttpClient::default());
+        assert!(alibaba.parse_ipv4_addresses_from_metadata(&mac_data).is_err());
+
+        let mac_data = AlibabaMacData { netmask: "255.255.255.0".to_string(), ..mac_data };
+        let addresses = alibaba.parse_ipv4_addresses_from_metadata(&mac_data).unwrap();
+        assert_eq!(addresses.len(), 2);
+        assert!(addresses.contains_key("172.16.0.11/24"));
+    }
+}
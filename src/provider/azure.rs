@@ -1,11 +1,19 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
-use anyhow::Result;
+use crate::network::Link;
+use crate::web::{HttpClient, MetadataClient};
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
 
-const AZURE_METADATA_BASE: &str = "http://169.254.169.254/metadata/instance";
+const AZURE_SCHEDULED_EVENTS_PATH: &str = "scheduledevents?api-version=2020-07-01";
+
+// Event types that take the VM away, the only ones worth draining for
+const AZURE_DISRUPTIVE_EVENTS: &[&str] = &["Reboot", "Redeploy", "Freeze", "Preempt", "Terminate"];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AzureMetadata {
@@ -36,6 +44,8 @@ pub struct AzureNetwork {
 pub struct AzureInterface {
     pub mac_address: String,
     pub ipv4: AzureIpv4,
+    #[serde(default)]
+    pub ipv6: AzureIpv6,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,6 +59,22 @@ pub struct AzureIpv4 {
 #[serde(rename_all = "camelCase")]
 pub struct AzureIpAddress {
     pub private_ip_address: String,
+    #[serde(default)]
+    pub public_ip_address: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureIpv6 {
+    #[serde(default)]
+    pub ip_address: Vec<AzureIpv6Address>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureIpv6Address {
+    pub private_ip_address: String,
+    #[serde(default)]
     pub public_ip_address: String,
 }
 
@@ -58,83 +84,450 @@ pub struct AzureSubnet {
     pub prefix: String,
 }
 
+/// Per-link state saved for consumers such as cnctl, with the MAC in the
+/// same format as the kernel reports it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureLinkState {
+    pub mac_address: String,
+    pub private_ip_addresses: Vec<String>,
+    pub public_ip_addresses: Vec<String>,
+    pub ipv6_addresses: Vec<String>,
+    pub ipv6_public_ip_addresses: Vec<String>,
+    pub subnets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct AzureScheduledEvents {
+    pub document_incarnation: u64,
+    pub events: Vec<AzureScheduledEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AzureScheduledEvent {
+    pub event_id: String,
+    pub event_type: String,
+    pub resource_type: String,
+    #[serde(default)]
+    pub resources: Vec<String>,
+    pub event_status: String,
+    #[serde(default)]
+    pub not_before: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub event_source: String,
+    #[serde(default)]
+    pub duration_in_seconds: i64,
+}
+
+pub type ScheduledEventsState = Arc<Mutex<AzureScheduledEvents>>;
+
 pub struct Azure {
     metadata: Option<AzureMetadata>,
     api_version: String,
+    client: MetadataClient,
 }
 
 impl Azure {
-    pub fn new(config: &crate::conf::AzureCloudConfig) -> Self {
+    pub fn new(config: &crate::conf::AzureCloudConfig, client: HttpClient) -> Self {
         Self {
             metadata: None,
             api_version: config.api_version.clone(),
+            client: metadata_client(config, client),
         }
     }
 
+    fn find_interface_by_mac(&self, mac: &str) -> Option<&AzureInterface> {
+        let meta = self.metadata.as_ref()?;
+        let mac = normalize_mac(mac);
+        meta.network
+            .interface
+            .iter()
+            .find(|iface| normalize_mac(&iface.mac_address) == mac)
+    }
+
     fn parse_ipv4_addresses_from_metadata_by_mac(&self, mac: &str) -> HashMap<String, bool> {
         let mut addresses = HashMap::new();
 
-        if let Some(ref meta) = self.metadata {
-            for iface in &meta.network.interface {
-                if iface.mac_address.eq_ignore_ascii_case(mac) {
-                    if let Some(subnet) = iface.ipv4.subnet.first() {
-                        let prefix = &subnet.prefix;
-                        for ip_addr in &iface.ipv4.ip_address {
-                            let cidr = format!("{}/{}", ip_addr.private_ip_address, prefix);
-                            addresses.insert(cidr, true);
-                        }
+        if let Some(iface) = self.find_interface_by_mac(mac) {
+            for ip_addr in &iface.ipv4.ip_address {
+                match subnet_prefix_for_address(&iface.ipv4.subnet, &ip_addr.private_ip_address) {
+                    Some(prefix) => {
+                        let cidr = format!("{}/{}", ip_addr.private_ip_address, prefix);
+                        addresses.insert(cidr, true);
                     }
-                    break;
+                    None => tracing::warn!(
+                        "No subnet found for address='{}' mac='{}', ignoring",
+                        ip_addr.private_ip_address, mac
+                    ),
                 }
             }
         }
 
         addresses
     }
+
+    fn parse_ipv6_addresses_from_metadata_by_mac(&self, mac: &str) -> HashMap<String, bool> {
+        let mut addresses = HashMap::new();
+
+        if let Some(iface) = self.find_interface_by_mac(mac) {
+            // Azure hands out IPv6 addresses via DHCPv6 as /128
+            for ip_addr in &iface.ipv6.ip_address {
+                let cidr = format!("{}/128", ip_addr.private_ip_address);
+                addresses.insert(cidr, true);
+            }
+        }
+
+        addresses
+    }
+
+    fn link_state(&self, iface: &AzureInterface) -> AzureLinkState {
+        let non_empty = |ip: &String| if ip.is_empty() { None } else { Some(ip.clone()) };
+
+        AzureLinkState {
+            mac_address: normalize_mac(&iface.mac_address),
+            private_ip_addresses: self
+                .parse_ipv4_addresses_from_metadata_by_mac(&iface.mac_address)
+                .into_keys()
+                .collect(),
+            public_ip_addresses: iface
+                .ipv4
+                .ip_address
+                .iter()
+                .filter_map(|ip| non_empty(&ip.public_ip_address))
+                .collect(),
+            ipv6_addresses: iface
+                .ipv6
+                .ip_address
+                .iter()
+                .filter_map(|ip| non_empty(&ip.private_ip_address))
+                .collect(),
+            ipv6_public_ip_addresses: iface
+                .ipv6
+                .ip_address
+                .iter()
+                .filter_map(|ip| non_empty(&ip.public_ip_address))
+                .collect(),
+            subnets: iface
+                .ipv4
+                .subnet
+                .iter()
+                .map(|subnet| format!("{}/{}", subnet.address, subnet.prefix))
+                .collect(),
+        }
+    }
+}
+
+fn metadata_client(config: &crate::conf::AzureCloudConfig, client: HttpClient) -> MetadataClient {
+    MetadataClient::new(client, &config.endpoint).with_header("Metadata", "true")
+}
+
+/// Azure IMDS reports MACs without separators (000D3A5D2D66), the kernel as
+/// 00:0d:3a:5d:2d:66
+fn normalize_mac(mac: &str) -> String {
+    let stripped: String = mac.chars().filter(|c| *c != ':' && *c != '-').collect();
+    crate::parser::parse_mac(&stripped).to_lowercase()
+}
+
+/// Returns the prefix of the subnet the address belongs to. An address
+/// outside every subnet gets none, a guessed prefix would install wrong rules.
+fn subnet_prefix_for_address(subnets: &[AzureSubnet], address: &str) -> Option<String> {
+    subnets
+        .iter()
+        .find(|subnet| {
+            let cidr = format!("{}/{}", subnet.address, subnet.prefix);
+            crate::parser::cidr_contains(&cidr, address)
+        })
+        .map(|subnet| subnet.prefix.clone())
+}
+
+/// Returns the scheduled, disruptive events affecting this VM which have not
+/// been acknowledged yet
+fn events_to_acknowledge<'a>(
+    events: &'a AzureScheduledEvents,
+    vm_name: &str,
+    acknowledged: &HashSet<String>,
+) -> Vec<&'a AzureScheduledEvent> {
+    events
+        .events
+        .iter()
+        .filter(|event| event.event_status == "Scheduled")
+        .filter(|event| AZURE_DISRUPTIVE_EVENTS.contains(&event.event_type.as_str()))
+        .filter(|event| event.resources.is_empty() || event.resources.iter().any(|r| r == vm_name))
+        .filter(|event| !acknowledged.contains(&event.event_id))
+        .collect()
+}
+
+async fn run_drain_hook(hook: &str, event: &AzureScheduledEvent, timeout: Duration) -> Result<()> {
+    let mut child = tokio::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(hook)
+        .env("CLOUD_NETCONFIG_EVENT_ID", &event.event_id)
+        .env("CLOUD_NETCONFIG_EVENT_TYPE", &event.event_type)
+        .env("CLOUD_NETCONFIG_EVENT_NOT_BEFORE", &event.not_before)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run drain hook: {}", e))?;
+
+    let status = tokio::time::timeout(timeout, child.wait())
+        .await
+        .map_err(|_| anyhow!("Drain hook timed out after {:?}", timeout))??;
+
+    if !status.success() {
+        return Err(anyhow!("Drain hook exited with {}", status));
+    }
+
+    Ok(())
+}
+
+async fn acknowledge_event(client: &MetadataClient, event_id: &str) -> Result<()> {
+    let body = serde_json::json!({ "StartRequests": [{ "EventId": event_id }] });
+    client.post_json(AZURE_SCHEDULED_EVENTS_PATH, &body).await?;
+    Ok(())
+}
+
+/// Runs the drain hook, if any, and acknowledges the event only once it
+/// succeeded
+async fn drain_and_acknowledge(
+    client: &MetadataClient,
+    event: &AzureScheduledEvent,
+    hook: Option<&str>,
+    timeout: Duration,
+) -> Result<()> {
+    if let Some(hook) = hook {
+        tracing::info!("Running drain hook for event id='{}' type='{}'", event.event_id, event.event_type);
+
+        run_drain_hook(hook, event, timeout)
+            .await
+            .map_err(|e| anyhow!("Drain hook failed, not acknowledging: {}", e))?;
+    }
+
+    acknowledge_event(client, &event.event_id).await
+}
+
+async fn fetch_vm_name(client: &MetadataClient, api_version: &str) -> Result<String> {
+    client
+        .get_text(&format!("instance/compute/name?api-version={}&format=text", api_version))
+        .await
+}
+
+/// Polls Azure Scheduled Events, publishing them in `state` and optionally
+/// acknowledging disruptive ones once the drain hook succeeded
+pub async fn watch_scheduled_events(config: crate::conf::Config, state: ScheduledEventsState) {
+    let events_config = &config.cloud.azure.scheduled_events;
+    let client = metadata_client(&config.cloud.azure, HttpClient::from_config(&config));
+
+    tracing::info!("Azure scheduled events watching started");
+
+    // Events listing resources can only be matched once the name is known,
+    // so a failed fetch is retried every poll
+    let mut vm_name: Option<String> = None;
+    let mut acknowledged: HashSet<String> = HashSet::new();
+    let mut interval = tokio::time::interval(config.get_scheduled_events_interval());
+
+    loop {
+        interval.tick().await;
+
+        if vm_name.is_none() {
+            match fetch_vm_name(&client, &config.cloud.azure.api_version).await {
+                Ok(name) => vm_name = Some(name),
+                Err(e) => tracing::warn!("Failed to fetch VM name for scheduled events: {}", e),
+            }
+        }
+
+        let events = match client.get_json::<AzureScheduledEvents>(AZURE_SCHEDULED_EVENTS_PATH).await {
+            Ok(events) => events,
+            Err(e) => {
+                tracing::warn!("Scheduled events request failed: {}", e);
+                continue;
+            }
+        };
+
+        {
+            let mut state_guard = state.lock().await;
+            if state_guard.document_incarnation != events.document_incarnation {
+                for event in &events.events {
+                    tracing::info!(
+                        "Scheduled event id='{}' type='{}' status='{}' not_before='{}'",
+                        event.event_id, event.event_type, event.event_status, event.not_before
+                    );
+                }
+            }
+            *state_guard = events.clone();
+        }
+
+        // Forget events Azure no longer reports
+        acknowledged.retain(|id| events.events.iter().any(|event| &event.event_id == id));
+
+        if !events_config.acknowledge {
+            continue;
+        }
+
+        let name = vm_name.as_deref().unwrap_or_default();
+        for event in events_to_acknowledge(&events, name, &acknowledged) {
+            let hook = events_config.drain_hook.as_deref();
+
+            match drain_and_acknowledge(&client, event, hook, config.get_drain_timeout()).await {
+                Ok(()) => {
+                    tracing::info!("Acknowledged scheduled event id='{}' type='{}'", event.event_id, event.event_type);
+                    acknowledged.insert(event.event_id.clone());
+                }
+                Err(e) => tracing::error!("Failed to acknowledge scheduled event id='{}': {}", event.event_id, e),
+            }
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl super::CloudProvider for Azure {
     async fn fetch_cloud_metadata(&mut self) -> Result<()> {
-        let url = format!("{}?api-version={}", AZURE_METADATA_BASE, self.api_version);
-        let client = reqwest::Client::new();
-        let response = client
-            .get(&url)
-            .header("Metadata", "true")
-            .send()
-            .await?;
-
-        self.metadata = Some(response.json::<AzureMetadata>().await?);
+        let path = format!("instance?api-version={}", self.api_version);
+        self.metadata = Some(self.client.get_json::<AzureMetadata>(&path).await?);
         Ok(())
     }
 
     async fn configure_network_from_cloud_meta(&self, env: &mut super::Environment) -> Result<()> {
-        for (mac, link) in &env.links.links_by_mac {
-            let addresses = self.parse_ipv4_addresses_from_metadata_by_mac(mac);
-            if !addresses.is_empty() {
-                super::network::configure_network(env, link, addresses, None, None).await?;
-            }
+        let links: Vec<Link> = env.links.links_by_mac.values().cloned().collect();
+        for link in &links {
+            self.configure_link_from_cloud_meta(env, link).await?;
+        }
+        Ok(())
+    }
+
+    async fn configure_link_from_cloud_meta(&self, env: &mut super::Environment, link: &Link) -> Result<()> {
+        // With accelerated networking the VF shares the MAC of the synthetic
+        // hv_netvsc NIC, only the latter is configured
+        if link.is_enslaved() {
+            tracing::debug!(
+                "Skipping enslaved link='{}' ifindex='{}' driver='{}'",
+                link.name, link.ifindex, link.driver
+            );
+            return Ok(());
+        }
+
+        let mut addresses = self.parse_ipv4_addresses_from_metadata_by_mac(&link.mac);
+        addresses.extend(self.parse_ipv6_addresses_from_metadata_by_mac(&link.mac));
+        if !addresses.is_empty() {
+            super::network::configure_network(env, link, addresses, HashMap::new(), None, None, None).await?;
         }
         Ok(())
     }
 
     async fn save_cloud_metadata(&self) -> Result<()> {
-        if let Some(ref meta) = self.metadata {
+        if let Some(system) = self.system_metadata() {
             let path = format!("{}/azure", crate::conf::SYSTEM_STATE_DIR);
-            crate::system::create_and_save_json(&path, &meta.compute)?;
+            crate::system::create_and_save_json(&path, &system)?;
         }
         Ok(())
     }
 
     async fn link_save_cloud_metadata(&self, env: &super::Environment) -> Result<()> {
-        if let Some(ref meta) = self.metadata {
-            for iface in &meta.network.interface {
-                if let Some(link) = env.links.links_by_mac.get(&iface.mac_address) {
-                    let path = format!("{}/{}", crate::conf::LINK_STATE_DIR, link.name);
-                    crate::system::create_and_save_json(&path, iface)?;
-                }
+        for link in env.links.links_by_mac.values() {
+            if let Some(state) = self.link_metadata(link) {
+                let path = format!("{}/{}", crate::conf::LINK_STATE_DIR, link.name);
+                crate::system::create_and_save_json(&path, &state)?;
             }
         }
         Ok(())
     }
+
+    fn system_metadata(&self) -> Option<serde_json::Value> {
+        let meta = self.metadata.as_ref()?;
+        serde_json::to_value(&meta.compute).ok()
+    }
+
+    fn link_metadata(&self, link: &Link) -> Option<serde_json::Value> {
+        let iface = self.find_interface_by_mac(&link.mac)?;
+        serde_json::to_value(self.link_state(iface)).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_mac() {
+        assert_eq!(normalize_mac("000D3A5D2D66"), "00:0d:3a:5d:2d:66");
+        assert_eq!(normalize_mac("00:0D:3A:5D:2D:66"), "00:0d:3a:5d:2d:66");
+    }
+
+    #[test]
+    fn test_subnet_prefix_for_address() {
+        let subnets = vec![
+            AzureSubnet { address: "10.0.0.0".to_string(), prefix: "24".to_string() },
+            AzureSubnet { address: "10.0.1.0".to_string(), prefix: "26".to_string() },
+        ];
+
+        assert_eq!(subnet_prefix_for_address(&subnets, "10.0.1.5").as_deref(), Some("26"));
+        assert_eq!(subnet_prefix_for_address(&subnets, "10.0.0.5").as_deref(), Some("24"));
+        assert_eq!(subnet_prefix_for_address(&subnets, "192.168.0.5"), None);
+        assert_eq!(subnet_prefix_for_address(&[], "10.0.0.5"), None);
+    }
+
+    #[test]
+    fn test_events_to_acknowledge() {
+        let events: AzureScheduledEvents = serde_json::from_str(
+            r#"{
+                "DocumentIncarnation": 2,
+                "Events": [
+                    {"EventId": "A", "EventType": "Reboot", "ResourceType": "VirtualMachine",
+                     "Resources": ["vm1"], "EventStatus": "Scheduled", "NotBefore": "Mon, 19 Sep 2016 18:29:47 GMT"},
+                    {"EventId": "B", "EventType": "Freeze", "ResourceType": "VirtualMachine",
+                     "Resources": ["vm2"], "EventStatus": "Scheduled", "NotBefore": ""},
+                    {"EventId": "C", "EventType": "Redeploy", "ResourceType": "VirtualMachine",
+                     "Resources": ["vm1"], "EventStatus": "Started", "NotBefore": ""}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let ids: Vec<&str> = events_to_acknowledge(&events, "vm1", &HashSet::new())
+            .iter()
+            .map(|event| event.event_id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["A"]);
+
+        let acknowledged = HashSet::from(["A".to_string()]);
+        assert!(events_to_acknowledge(&events, "vm1", &acknowledged).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_drain_hook_blocks_acknowledgement() {
+        let mut server = mockito::Server::new_async().await;
+
+        let acknowledge = server
+            .mock("POST", "/metadata/scheduledevents")
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"StartRequests": [{"EventId": "A"}]}"#.to_string(),
+            ))
+            .expect(1)
+            .create_async()
+            .await;
+
+        let config = crate::conf::AzureCloudConfig {
+            endpoint: format!("{}/metadata", server.url()),
+            ..Default::default()
+        };
+        let client = metadata_client(&config, HttpClient::default());
+
+        let event: AzureScheduledEvent = serde_json::from_str(
+            r#"{"EventId": "A", "EventType": "Reboot", "ResourceType": "VirtualMachine",
+                "Resources": ["vm1"], "EventStatus": "Scheduled", "NotBefore": ""}"#,
+        )
+        .unwrap();
+        let timeout = Duration::from_secs(5);
+
+        // Failing hook, the event must not be acknowledged
+        assert!(drain_and_acknowledge(&client, &event, Some("exit 1"), timeout).await.is_err());
+
+        // Succeeding hook, acknowledged once
+        assert!(drain_and_acknowledge(&client, &event, Some("exit 0"), timeout).await.is_ok());
+
+        acknowledge.assert_async().await;
+    }
 }
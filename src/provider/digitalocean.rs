@@ -0,0 +1,252 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use crate::network::Link;
+use crate::web::{HttpClient, MetadataClient};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const DIGITALOCEAN_METADATA_PATH: &str = "v1.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigitalOceanMetadata {
+    pub droplet_id: u64,
+    pub hostname: String,
+    pub region: String,
+    pub interfaces: DigitalOceanInterfaces,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct DigitalOceanInterfaces {
+    #[serde(default)]
+    pub public: Vec<DigitalOceanInterface>,
+    #[serde(default)]
+    pub private: Vec<DigitalOceanInterface>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigitalOceanInterface {
+    pub mac: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub ipv4: Option<DigitalOceanIpv4>,
+    pub anchor_ipv4: Option<DigitalOceanIpv4>,
+    pub ipv6: Option<DigitalOceanIpv6>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigitalOceanIpv4 {
+    pub ip_address: String,
+    pub netmask: String,
+    #[serde(default)]
+    pub gateway: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigitalOceanIpv6 {
+    pub ip_address: String,
+    pub cidr: u8,
+    #[serde(default)]
+    pub gateway: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigitalOceanSystem {
+    pub droplet_id: u64,
+    pub hostname: String,
+    pub region: String,
+}
+
+pub struct DigitalOcean {
+    metadata: Option<DigitalOceanMetadata>,
+    client: MetadataClient,
+}
+
+impl DigitalOcean {
+    pub fn new(config: &crate::conf::DigitalOceanCloudConfig, client: HttpClient) -> Self {
+        Self {
+            metadata: None,
+            client: MetadataClient::new(client, &config.endpoint),
+        }
+    }
+
+    fn find_interface_by_mac(&self, mac: &str) -> Option<&DigitalOceanInterface> {
+        let meta = self.metadata.as_ref()?;
+        meta.interfaces
+            .public
+            .iter()
+            .chain(&meta.interfaces.private)
+            .find(|iface| iface.mac.eq_ignore_ascii_case(mac))
+    }
+}
+
+fn parse_addresses_from_interface(iface: &DigitalOceanInterface) -> HashMap<String, bool> {
+    let mut addresses = HashMap::new();
+
+    // Anchor IP is the private address reserved IPs are mapped to
+    for ipv4 in iface.ipv4.iter().chain(&iface.anchor_ipv4) {
+        // A guessed prefix would install wrong rules, skip the address
+        match crate::parser::parse_netmask(&ipv4.netmask) {
+            Ok(prefix) => {
+                addresses.insert(format!("{}/{}", ipv4.ip_address, prefix), true);
+            }
+            Err(e) => tracing::warn!(
+                "Skipping address='{}' mac='{}', invalid netmask '{}': {}",
+                ipv4.ip_address,
+                iface.mac,
+                ipv4.netmask,
+                e
+            ),
+        }
+    }
+
+    if let Some(ref ipv6) = iface.ipv6 {
+        addresses.insert(format!("{}/{}", ipv6.ip_address, ipv6.cidr), true);
+    }
+
+    addresses
+}
+
+#[async_trait::async_trait]
+impl super::CloudProvider for DigitalOcean {
+    async fn fetch_cloud_metadata(&mut self) -> Result<()> {
+        self.metadata = Some(
+            self.client
+                .get_json::<DigitalOceanMetadata>(DIGITALOCEAN_METADATA_PATH)
+                .await?,
+        );
+        Ok(())
+    }
+
+    async fn configure_network_from_cloud_meta(&self, env: &mut super::Environment) -> Result<()> {
+        let links: Vec<Link> = env.links.links_by_mac.values().cloned().collect();
+        for link in &links {
+            self.configure_link_from_cloud_meta(env, link).await?;
+        }
+        Ok(())
+    }
+
+    async fn configure_link_from_cloud_meta(&self, env: &mut super::Environment, link: &Link) -> Result<()> {
+        let iface = match self.find_interface_by_mac(&link.mac) {
+            Some(iface) => iface,
+            None => return Ok(()),
+        };
+
+        let addresses = parse_addresses_from_interface(iface);
+        if !addresses.is_empty() {
+            let gateway = iface
+                .ipv4
+                .as_ref()
+                .map(|ipv4| ipv4.gateway.clone())
+                .filter(|gw| !gw.is_empty());
+            let gateway6 = iface
+                .ipv6
+                .as_ref()
+                .map(|ipv6| ipv6.gateway.clone())
+                .filter(|gw| !gw.is_empty());
+
+            super::network::configure_network(env, link, addresses, HashMap::new(), gateway, gateway6, None).await?;
+        }
+        Ok(())
+    }
+
+    async fn save_cloud_metadata(&self) -> Result<()> {
+        if let Some(system) = self.system_metadata() {
+            let path = format!("{}/digitalocean", crate::conf::SYSTEM_STATE_DIR);
+            crate::system::create_and_save_json(&path, &system)?;
+        }
+        Ok(())
+    }
+
+    async fn link_save_cloud_metadata(&self, env: &super::Environment) -> Result<()> {
+        for link in env.links.links_by_mac.values() {
+            if let Some(iface) = self.find_interface_by_mac(&link.mac) {
+                let path = format!("{}/{}", crate::conf::LINK_STATE_DIR, link.name);
+                crate::system::create_and_save_json(&path, iface)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn system_metadata(&self) -> Option<serde_json::Value> {
+        let meta = self.metadata.as_ref()?;
+        let system = DigitalOceanSystem {
+            droplet_id: meta.droplet_id,
+            hostname: meta.hostname.clone(),
+            region: meta.region.clone(),
+        };
+        serde_json::to_value(system).ok()
+    }
+
+    fn link_metadata(&self, link: &Link) -> Option<serde_json::Value> {
+        serde_json::to_value(self.find_interface_by_mac(&link.mac)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As served by /metadata/v1.json
+    const METADATA: &str = r#"{
+        "droplet_id": 2756294,
+        "hostname": "sample-droplet",
+        "region": "nyc3",
+        "interfaces": {
+            "public": [{
+                "ipv4": {"ip_address": "104.131.20.105", "netmask": "255.255.192.0", "gateway": "104.131.0.1"},
+                "anchor_ipv4": {"ip_address": "10.17.0.5", "netmask": "255.255.0.0", "gateway": "10.17.0.1"},
+                "ipv6": {"ip_address": "2604:A880:0800:0010:0000:0000:017D:2001", "cidr": 64, "gateway": "2604:A880:0800:0010:0000:0000:0000:0001"},
+                "mac": "04:01:2a:0f:2a:01",
+                "type": "public"
+            }],
+            "private": [{
+                "ipv4": {"ip_address": "10.132.255.113", "netmask": "255.255.0.0", "gateway": "0.0.0.0"},
+                "mac": "04:01:2a:0f:2a:02",
+                "type": "private"
+            }]
+        }
+    }"#;
+
+    fn digitalocean() -> DigitalOcean {
+        let mut digitalocean =
+            DigitalOcean::new(&crate::conf::DigitalOceanCloudConfig::default(), HttpClient::default());
+        digitalocean.metadata = Some(serde_json::from_str(METADATA).unwrap());
+        digitalocean
+    }
+
+    #[test]
+    fn test_public_interface_addresses() {
+        let digitalocean = digitalocean();
+        let iface = digitalocean.find_interface_by_mac("04:01:2A:0F:2A:01").unwrap();
+        assert_eq!(iface.kind, "public");
+
+        let addresses = parse_addresses_from_interface(iface);
+        assert_eq!(addresses.len(), 3);
+        assert!(addresses.contains_key("104.131.20.105/18"));
+        // Anchor IP sits on the public interface
+        assert!(addresses.contains_key("10.17.0.5/16"));
+        assert!(addresses.contains_key("2604:A880:0800:0010:0000:0000:017D:2001/64"));
+    }
+
+    #[test]
+    fn test_private_interface_addresses() {
+        let digitalocean = digitalocean();
+        let iface = digitalocean.find_interface_by_mac("04:01:2a:0f:2a:02").unwrap();
+        assert_eq!(iface.kind, "private");
+
+        let addresses = parse_addresses_from_interface(iface);
+        assert_eq!(addresses, HashMap::from([("10.132.255.113/16".to_string(), true)]));
+    }
+
+    #[test]
+    fn test_invalid_netmask_is_skipped() {
+        let digitalocean = digitalocean();
+        let mut iface = digitalocean.find_interface_by_mac("04:01:2a:0f:2a:01").unwrap().clone();
+        iface.anchor_ipv4.as_mut().unwrap().netmask = "255.0.255.0".to_string();
+
+        let addresses = parse_addresses_from_interface(&iface);
+        assert_eq!(addresses.len(), 2);
+        assert!(!addresses.keys().any(|addr| addr.starts_with("10.17.0.5")));
+    }
+}
@@ -1,11 +1,23 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
-use anyhow::Result;
+use crate::network::Link;
+use crate::web::{HttpClient, HttpStatusError, MetadataClient};
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-const EC2_METADATA_ENDPOINT: &str = "http://169.254.169.254/latest/meta-data";
+const EC2_METADATA_PATH: &str = "meta-data";
+const EC2_TOKEN_PATH: &str = "api/token";
+
+const EC2_TOKEN_TTL_HEADER: &str = "X-aws-ec2-metadata-token-ttl-seconds";
+const EC2_TOKEN_HEADER: &str = "X-aws-ec2-metadata-token";
+
+pub const DEFAULT_TOKEN_TTL: u64 = 21600;
+
+// Renew the session token a bit before it actually expires
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct EC2System {
@@ -15,35 +27,223 @@ pub struct EC2System {
     pub public_ipv4: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct EC2MacData {
     pub mac: String,
-    pub local_ipv4s: Vec<String>,
+    pub device_number: u32,
+    pub interface_id: String,
+    pub vpc_id: String,
+    pub vpc_ipv4_cidr_blocks: Vec<String>,
+    pub subnet_id: String,
     pub subnet_ipv4_cidr_block: String,
+    pub security_group_ids: Vec<String>,
+    pub local_ipv4s: Vec<String>,
+    pub public_ipv4s: Vec<String>,
+    pub ipv6s: Vec<String>,
+    pub ipv4_prefix: Vec<String>,
+    pub ipv6_prefix: Vec<String>,
+}
+
+struct SessionToken {
+    value: String,
+    expires_at: Instant,
 }
 
 pub struct EC2 {
-    system: HashMap<String, serde_json::Value>,
-    network: HashMap<String, serde_json::Value>,
+    system: EC2System,
     macs: HashMap<String, EC2MacData>,
     imds_version: u8,
+    token_ttl: u64,
+    token: Option<SessionToken>,
+    /// IMDSv2 was unavailable during this pass, stay on IMDSv1 until the next
+    imds_v1_fallback: bool,
+    prefix_delegation: String,
+    client: MetadataClient,
 }
 
 impl EC2 {
-    pub fn new(config: &crate::conf::AwsCloudConfig) -> Self {
+    pub fn new(config: &crate::conf::AwsCloudConfig, client: HttpClient) -> Self {
         Self {
-            system: HashMap::new(),
-            network: HashMap::new(),
+            system: EC2System::default(),
             macs: HashMap::new(),
             imds_version: config.imds_version,
+            token_ttl: config.token_ttl.unwrap_or(DEFAULT_TOKEN_TTL),
+            token: None,
+            imds_v1_fallback: false,
+            prefix_delegation: config.prefix_delegation.clone(),
+            client: MetadataClient::new(client, &config.endpoint),
+        }
+    }
+
+    /// Returns a cached IMDSv2 session token, requesting a new one when
+    /// there is none or it is about to expire
+    async fn fetch_token(&mut self) -> Result<String> {
+        if let Some(ref token) = self.token {
+            if Instant::now() + TOKEN_REFRESH_MARGIN < token.expires_at {
+                return Ok(token.value.clone());
+            }
+        }
+
+        // With IMDSv1 allowed a missing token is not worth retrying for
+        let client = if self.imds_version == 1 {
+            self.client.without_retry()
+        } else {
+            self.client.clone()
+        };
+
+        let headers = HashMap::from([(EC2_TOKEN_TTL_HEADER.to_string(), self.token_ttl.to_string())]);
+        let body = client
+            .request(reqwest::Method::PUT, EC2_TOKEN_PATH, &headers, None)
+            .await
+            .context("Failed to acquire IMDSv2 session token")?;
+        let value = String::from_utf8_lossy(&body).trim().to_string();
+
+        self.token = Some(SessionToken {
+            value: value.clone(),
+            expires_at: Instant::now() + Duration::from_secs(self.token_ttl),
+        });
+
+        Ok(value)
+    }
+
+    async fn fetch_metadata_simple(&mut self, path: &str) -> Result<String> {
+        let path = format!("{}/{}", EC2_METADATA_PATH, path);
+
+        if self.imds_v1_fallback {
+            return self.client.get_text(&path).await;
+        }
+
+        let token = match self.fetch_token().await {
+            Ok(token) => token,
+            Err(e) if self.imds_version == 1 => {
+                tracing::debug!("IMDSv2 unavailable, falling back to IMDSv1: {}", e);
+                self.imds_v1_fallback = true;
+                return self.client.get_text(&path).await;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let headers = HashMap::from([(EC2_TOKEN_HEADER.to_string(), token)]);
+        let body = match self
+            .client
+            .request(reqwest::Method::GET, &path, &headers, None)
+            .await
+        {
+            // Token was invalidated (e.g. IMDS restarted), get a fresh one and retry once
+            Err(e) if is_unauthorized(&e) => {
+                self.token = None;
+                let headers = HashMap::from([(EC2_TOKEN_HEADER.to_string(), self.fetch_token().await?)]);
+                self.client
+                    .request(reqwest::Method::GET, &path, &headers, None)
+                    .await?
+            }
+            body => body?,
+        };
+
+        Ok(String::from_utf8_lossy(&body).trim().to_string())
+    }
+
+    /// Fetches a newline separated list
+    async fn fetch_metadata_list(&mut self, path: &str) -> Result<Vec<String>> {
+        let text = self.fetch_metadata_simple(path).await?;
+        Ok(text
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect())
+    }
+
+    /// Optional lists (e.g. public-ipv4s) are not present in the metadata
+    /// tree when empty. Only a 404 means empty, any other failure is an
+    /// error: an empty list would remove what the daemon manages.
+    async fn fetch_metadata_optional_list(&mut self, path: &str) -> Result<Vec<String>> {
+        match self.fetch_metadata_list(path).await {
+            Err(e) if is_not_found(&e) => Ok(Vec::new()),
+            result => result.with_context(|| format!("Failed to fetch {}", path)),
         }
     }
 
-    async fn fetch_metadata_simple(&self, path: &str) -> Result<String> {
-        let url = format!("{}/{}", EC2_METADATA_ENDPOINT, path);
-        let client = reqwest::Client::new();
-        let text = client.get(&url).send().await?.text().await?;
-        Ok(text)
+    async fn fetch_mac_data(&mut self, mac: &str) -> Result<EC2MacData> {
+        let base = format!("network/interfaces/macs/{}", mac);
+
+        let device_number = self
+            .fetch_metadata_simple(&format!("{}/device-number", base))
+            .await
+            .context("Failed to fetch device-number")?
+            .trim()
+            .parse::<u32>()
+            .context("Invalid device-number")?;
+
+        let interface_id = self
+            .fetch_metadata_simple(&format!("{}/interface-id", base))
+            .await
+            .context("Failed to fetch interface-id")?;
+
+        let vpc_id = self
+            .fetch_metadata_simple(&format!("{}/vpc-id", base))
+            .await
+            .context("Failed to fetch vpc-id")?;
+
+        let vpc_ipv4_cidr_blocks = self
+            .fetch_metadata_list(&format!("{}/vpc-ipv4-cidr-blocks", base))
+            .await
+            .context("Failed to fetch vpc-ipv4-cidr-blocks")?;
+
+        let subnet_id = self
+            .fetch_metadata_simple(&format!("{}/subnet-id", base))
+            .await
+            .context("Failed to fetch subnet-id")?;
+
+        let subnet_ipv4_cidr_block = self
+            .fetch_metadata_simple(&format!("{}/subnet-ipv4-cidr-block", base))
+            .await
+            .context("Failed to fetch subnet-ipv4-cidr-block")?
+            .trim()
+            .to_string();
+
+        crate::parser::parse_cidr(&subnet_ipv4_cidr_block)
+            .context("Invalid subnet-ipv4-cidr-block")?;
+
+        let local_ipv4s = self
+            .fetch_metadata_list(&format!("{}/local-ipv4s", base))
+            .await
+            .context("Failed to fetch local-ipv4s")?;
+
+        let security_group_ids = self
+            .fetch_metadata_optional_list(&format!("{}/security-group-ids", base))
+            .await?;
+
+        let public_ipv4s = self
+            .fetch_metadata_optional_list(&format!("{}/public-ipv4s", base))
+            .await?;
+
+        let ipv6s = self
+            .fetch_metadata_optional_list(&format!("{}/ipv6s", base))
+            .await?;
+
+        let ipv4_prefix = self
+            .fetch_metadata_optional_list(&format!("{}/ipv4-prefix", base))
+            .await?;
+
+        let ipv6_prefix = self
+            .fetch_metadata_optional_list(&format!("{}/ipv6-prefix", base))
+            .await?;
+
+        Ok(EC2MacData {
+            mac: mac.to_string(),
+            device_number,
+            interface_id: interface_id.trim().to_string(),
+            vpc_id: vpc_id.trim().to_string(),
+            vpc_ipv4_cidr_blocks,
+            subnet_id: subnet_id.trim().to_string(),
+            subnet_ipv4_cidr_block,
+            security_group_ids,
+            local_ipv4s,
+            public_ipv4s,
+            ipv6s,
+            ipv4_prefix,
+            ipv6_prefix,
+        })
     }
 
     fn parse_ipv4_addresses_from_metadata(&self, addresses: &str, cidr: &str) -> HashMap<String, bool> {
@@ -59,13 +259,73 @@ impl EC2 {
 
         result
     }
+
+    /// Splits delegated prefixes into addresses to assign and prefixes to
+    /// install as local routes, according to the prefix_delegation mode
+    fn parse_delegated_prefixes(&self, mac_data: &EC2MacData) -> (HashMap<String, bool>, HashMap<String, bool>) {
+        let mut addresses = HashMap::new();
+        let mut prefixes = HashMap::new();
+
+        match self.prefix_delegation.as_str() {
+            "route" => {
+                for prefix in mac_data.ipv4_prefix.iter().chain(&mac_data.ipv6_prefix) {
+                    prefixes.insert(prefix.clone(), true);
+                }
+            }
+            "address" => {
+                let subnet_prefix = mac_data.subnet_ipv4_cidr_block.split('/').nth(1).unwrap_or("24");
+                for prefix in &mac_data.ipv4_prefix {
+                    match crate::parser::expand_ipv4_prefix(prefix) {
+                        Ok(ips) => {
+                            for ip in ips {
+                                addresses.insert(format!("{}/{}", ip, subnet_prefix), true);
+                            }
+                        }
+                        Err(e) => tracing::warn!("Ignoring delegated prefix='{}': {}", prefix, e),
+                    }
+                }
+
+                // IPv6 prefixes are far too large to assign address by address
+                for prefix in &mac_data.ipv6_prefix {
+                    prefixes.insert(prefix.clone(), true);
+                }
+            }
+            _ => {}
+        }
+
+        (addresses, prefixes)
+    }
+}
+
+fn is_unauthorized(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<HttpStatusError>()
+        .map(|e| e.status == reqwest::StatusCode::UNAUTHORIZED)
+        .unwrap_or(false)
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<HttpStatusError>()
+        .map(|e| e.status == reqwest::StatusCode::NOT_FOUND)
+        .unwrap_or(false)
 }
 
 #[async_trait::async_trait]
 impl super::CloudProvider for EC2 {
     async fn fetch_cloud_metadata(&mut self) -> Result<()> {
-        // Simplified EC2 metadata fetching
-        // In a full implementation, this would recursively traverse the metadata tree
+        // Give IMDSv2 another chance every pass
+        self.imds_v1_fallback = false;
+
+        let instance_id = self.fetch_metadata_simple("instance-id").await?;
+        let instance_type = self.fetch_metadata_simple("instance-type").await?;
+        let local_ipv4 = self.fetch_metadata_simple("local-ipv4").await.unwrap_or_default();
+        let public_ipv4 = self.fetch_metadata_simple("public-ipv4").await.unwrap_or_default();
+
+        self.system = EC2System {
+            instance_id: instance_id.trim().to_string(),
+            instance_type: instance_type.trim().to_string(),
+            local_ipv4: local_ipv4.trim().to_string(),
+            public_ipv4: public_ipv4.trim().to_string(),
+        };
 
         // Fetch MACs
         let macs_text = self.fetch_metadata_simple("network/interfaces/macs/").await?;
@@ -76,42 +336,52 @@ impl super::CloudProvider for EC2 {
             .collect();
 
         // Fetch data for each MAC
+        let mut macs_data = HashMap::new();
         for mac in macs {
-            let local_ipv4s = self
-                .fetch_metadata_simple(&format!("network/interfaces/macs/{}/local-ipv4s", mac))
-                .await
-                .unwrap_or_default();
-
-            let subnet_cidr = self
-                .fetch_metadata_simple(&format!("network/interfaces/macs/{}/subnet-ipv4-cidr-block", mac))
+            let mac_data = self
+                .fetch_mac_data(&mac)
                 .await
-                .unwrap_or_else(|_| "10.0.0.0/24".to_string());
-
-            let mac_data = EC2MacData {
-                mac: mac.clone(),
-                local_ipv4s: local_ipv4s.lines().map(|s| s.to_string()).collect(),
-                subnet_ipv4_cidr_block: subnet_cidr,
-            };
+                .with_context(|| format!("Failed to fetch metadata for mac='{}'", mac))?;
 
-            self.macs.insert(mac, mac_data);
+            macs_data.insert(mac, mac_data);
         }
 
+        self.macs = macs_data;
+
         Ok(())
     }
 
     async fn configure_network_from_cloud_meta(&self, env: &mut super::Environment) -> Result<()> {
-        for (mac, mac_data) in &self.macs {
-            if let Some(link) = env.links.links_by_mac.get(mac) {
-                let addresses_str = mac_data.local_ipv4s.join(",");
-                let addresses = self.parse_ipv4_addresses_from_metadata(
-                    &addresses_str,
-                    &mac_data.subnet_ipv4_cidr_block,
-                );
-
-                if !addresses.is_empty() {
-                    super::network::configure_network(env, link, addresses, None, None).await?;
+        for mac in self.macs.keys() {
+            if let Some(link) = env.links.links_by_mac.get(mac).cloned() {
+                self.configure_link_from_cloud_meta(env, &link).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn configure_link_from_cloud_meta(&self, env: &mut super::Environment, link: &Link) -> Result<()> {
+        if let Some(mac_data) = self.macs.get(&link.mac) {
+            let addresses_str = mac_data.local_ipv4s.join(",");
+            let mut addresses = self.parse_ipv4_addresses_from_metadata(
+                &addresses_str,
+                &mac_data.subnet_ipv4_cidr_block,
+            );
+
+            // EC2 assigns IPv6 addresses via DHCPv6 as /128
+            for ipv6 in &mac_data.ipv6s {
+                let ipv6 = ipv6.trim();
+                if !ipv6.is_empty() {
+                    addresses.insert(format!("{}/128", ipv6), true);
                 }
             }
+
+            let (prefix_addresses, prefixes) = self.parse_delegated_prefixes(mac_data);
+            addresses.extend(prefix_addresses);
+
+            if !addresses.is_empty() || !prefixes.is_empty() {
+                super::network::configure_network(env, link, addresses, prefixes, None, None, None).await?;
+            }
         }
         Ok(())
     }
@@ -131,4 +401,161 @@ impl super::CloudProvider for EC2 {
         }
         Ok(())
     }
+
+    fn system_metadata(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&self.system).ok()
+    }
+
+    fn link_metadata(&self, link: &Link) -> Option<serde_json::Value> {
+        serde_json::to_value(self.macs.get(&link.mac)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::RetryPolicy;
+
+    #[tokio::test]
+    async fn test_imds_v1_fallback_is_cached() {
+        let mut server = mockito::Server::new_async().await;
+
+        let token = server
+            .mock("PUT", "/latest/api/token")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+
+        server
+            .mock("GET", "/latest/meta-data/instance-id")
+            .with_body("i-0123456789")
+            .expect(2)
+            .create_async()
+            .await;
+
+        let config = crate::conf::AwsCloudConfig {
+            imds_version: 1,
+            endpoint: format!("{}/latest/", server.url()),
+            ..Default::default()
+        };
+        let client = HttpClient::new(
+            Duration::from_secs(5),
+            RetryPolicy {
+                max_attempts: 3,
+                backoff: Duration::from_millis(10),
+            },
+        );
+        let mut ec2 = EC2::new(&config, client);
+
+        // One token attempt without retries, then IMDSv1 for the rest of the pass
+        assert_eq!(ec2.fetch_metadata_simple("instance-id").await.unwrap(), "i-0123456789");
+        assert_eq!(ec2.fetch_metadata_simple("instance-id").await.unwrap(), "i-0123456789");
+        token.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_optional_list_not_found_or_failed() {
+        let mut server = mockito::Server::new_async().await;
+        let base = "/latest/meta-data/network/interfaces/macs/02:00:00:00:00:01";
+
+        server
+            .mock("GET", format!("{}/ipv6s", base).as_str())
+            .with_status(404)
+            .create_async()
+            .await;
+
+        server
+            .mock("GET", format!("{}/ipv6-prefix", base).as_str())
+            .with_status(500)
+            .create_async()
+            .await;
+
+        server
+            .mock("GET", format!("{}/public-ipv4s", base).as_str())
+            .with_body("54.0.0.1\n54.0.0.2\n")
+            .create_async()
+            .await;
+
+        let config = crate::conf::AwsCloudConfig {
+            endpoint: format!("{}/latest/", server.url()),
+            ..Default::default()
+        };
+        let client = HttpClient::new(
+            Duration::from_secs(5),
+            RetryPolicy {
+                max_attempts: 1,
+                backoff: Duration::from_millis(10),
+            },
+        );
+        let mut ec2 = EC2::new(&config, client);
+        // No token endpoint, stay on IMDSv1
+        ec2.imds_v1_fallback = true;
+
+        let path = "network/interfaces/macs/02:00:00:00:00:01";
+
+        // Absent from the tree, nothing assigned
+        let ipv6s = ec2.fetch_metadata_optional_list(&format!("{}/ipv6s", path)).await;
+        assert!(ipv6s.unwrap().is_empty());
+
+        // A failed fetch must not look like an empty list
+        let prefixes = ec2.fetch_metadata_optional_list(&format!("{}/ipv6-prefix", path)).await;
+        assert!(prefixes.is_err());
+
+        let public = ec2.fetch_metadata_optional_list(&format!("{}/public-ipv4s", path)).await;
+        assert_eq!(public.unwrap(), vec!["54.0.0.1", "54.0.0.2"]);
+    }
+
+    fn delegated_mac_data() -> EC2MacData {
+        EC2MacData {
+            mac: "02:00:00:00:00:01".to_string(),
+            subnet_ipv4_cidr_block: "10.0.1.0/24".to_string(),
+            local_ipv4s: vec!["10.0.1.10".to_string()],
+            ipv4_prefix: vec!["10.0.1.16/28".to_string()],
+            ipv6_prefix: vec!["2600:1f18:abc:de00::/80".to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn ec2_with_prefix_delegation(mode: &str) -> EC2 {
+        let config = crate::conf::AwsCloudConfig {
+            prefix_delegation: mode.to_string(),
+            ..Default::default()
+        };
+        EC2::new(&config, HttpClient::default())
+    }
+
+    #[test]
+    fn test_prefix_delegation_route() {
+        let ec2 = ec2_with_prefix_delegation("route");
+        let (addresses, prefixes) = ec2.parse_delegated_prefixes(&delegated_mac_data());
+
+        assert!(addresses.is_empty());
+        assert_eq!(prefixes.len(), 2);
+        assert!(prefixes.contains_key("10.0.1.16/28"));
+        assert!(prefixes.contains_key("2600:1f18:abc:de00::/80"));
+    }
+
+    #[test]
+    fn test_prefix_delegation_address() {
+        let ec2 = ec2_with_prefix_delegation("address");
+        let (addresses, prefixes) = ec2.parse_delegated_prefixes(&delegated_mac_data());
+
+        // Every address of the IPv4 prefix, with the subnet's prefix length
+        assert_eq!(addresses.len(), 16);
+        assert!(addresses.contains_key("10.0.1.16/24"));
+        assert!(addresses.contains_key("10.0.1.31/24"));
+
+        // IPv6 prefixes are always routed
+        assert_eq!(prefixes, HashMap::from([("2600:1f18:abc:de00::/80".to_string(), true)]));
+    }
+
+    #[test]
+    fn test_prefix_delegation_none() {
+        let ec2 = ec2_with_prefix_delegation("none");
+        let (addresses, prefixes) = ec2.parse_delegated_prefixes(&delegated_mac_data());
+
+        assert!(addresses.is_empty());
+        assert!(prefixes.is_empty());
+    }
 }
@@ -1,11 +1,21 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
-use anyhow::Result;
+use crate::network::Link;
+use crate::web::{HttpClient, MetadataClient};
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
 
-const GCP_METADATA_ENDPOINT: &str = "http://metadata.google.internal/computeMetadata/v1/?recursive=true";
+// How long the metadata server holds a wait_for_change request open
+const GCP_WAIT_FOR_CHANGE_TIMEOUT: u64 = 60;
+
+// First delay before retrying after the long-poll request failed, doubled on
+// every consecutive failure up to the refresh interval
+const GCP_WATCH_RETRY_DELAY: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GCPMetadata {
@@ -29,8 +39,18 @@ pub struct GCPNetworkInterface {
     pub subnetmask: String,
     pub gateway: String,
     pub mtu: u32,
-    #[serde(default)]
+    #[serde(default, rename = "ipAliases")]
     pub ip_aliases: Vec<String>,
+    #[serde(default)]
+    pub ipv6: Vec<String>,
+    #[serde(default, rename = "ipv6Gateway")]
+    pub ipv6_gateway: Option<String>,
+    #[serde(default, rename = "forwardedIps")]
+    pub forwarded_ips: Vec<String>,
+    #[serde(default, rename = "forwardedIpv6s")]
+    pub forwarded_ipv6s: Vec<String>,
+    #[serde(default, rename = "targetInstanceIps")]
+    pub target_instance_ips: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,16 +63,25 @@ pub struct GCPProject {
 pub struct GCP {
     metadata: Option<GCPMetadata>,
     recursive: bool,
+    ip_aliases: String,
+    client: MetadataClient,
 }
 
 impl GCP {
-    pub fn new(config: &crate::conf::GcpCloudConfig) -> Self {
+    pub fn new(config: &crate::conf::GcpCloudConfig, client: HttpClient) -> Self {
         Self {
             metadata: None,
             recursive: config.recursive,
+            ip_aliases: config.ip_aliases.clone(),
+            client: Self::metadata_client(config, client),
         }
     }
 
+    /// Client for the metadata server, also used by watch_metadata
+    pub fn metadata_client(config: &crate::conf::GcpCloudConfig, client: HttpClient) -> MetadataClient {
+        MetadataClient::new(client, &config.endpoint).with_header("Metadata-Flavor", "Google")
+    }
+
     fn parse_ipv4_gateway_from_metadata_by_mac(&self, mac: &str) -> Option<String> {
         if let Some(ref meta) = self.metadata {
             for iface in &meta.instance.network_interfaces {
@@ -64,6 +93,28 @@ impl GCP {
         None
     }
 
+    fn parse_ipv6_gateway_from_metadata_by_mac(&self, mac: &str) -> Option<String> {
+        if let Some(ref meta) = self.metadata {
+            for iface in &meta.instance.network_interfaces {
+                if iface.mac.eq_ignore_ascii_case(mac) {
+                    return iface.ipv6_gateway.clone();
+                }
+            }
+        }
+        None
+    }
+
+    fn parse_subnet_mask_from_metadata_by_mac(&self, mac: &str) -> Option<String> {
+        if let Some(ref meta) = self.metadata {
+            for iface in &meta.instance.network_interfaces {
+                if iface.mac.eq_ignore_ascii_case(mac) {
+                    return Some(iface.subnetmask.clone());
+                }
+            }
+        }
+        None
+    }
+
     fn parse_link_mtu_from_metadata_by_mac(&self, mac: &str) -> Option<u32> {
         if let Some(ref meta) = self.metadata {
             for iface in &meta.instance.network_interfaces {
@@ -88,10 +139,13 @@ impl GCP {
                     let cidr = format!("{}/{}", iface.ip, prefix);
                     addresses.insert(cidr, true);
 
-                    // Add IP aliases
-                    for alias in &iface.ip_aliases {
-                        let alias_cidr = format!("{}/{}", alias, prefix);
-                        addresses.insert(alias_cidr, true);
+                    // Add IPv6 addresses, single addresses are assigned as /128
+                    for ipv6 in &iface.ipv6 {
+                        if ipv6.contains('/') {
+                            addresses.insert(ipv6.clone(), true);
+                        } else {
+                            addresses.insert(format!("{}/128", ipv6), true);
+                        }
                     }
                     break;
                 }
@@ -101,45 +155,180 @@ impl GCP {
         addresses
     }
 
-    fn subnet_mask_to_cidr(&self, mask: &str) -> u8 {
-        // Simple conversion from subnet mask to CIDR prefix length
-        let parts: Vec<u8> = mask.split('.').filter_map(|s| s.parse().ok()).collect();
-        if parts.len() != 4 {
-            return 24; // default
+    /// Alias IP entries are CIDR ranges (10.1.2.0/24), single hosts may come
+    /// without a prefix length
+    fn parse_alias_ranges_from_metadata_by_mac(&self, mac: &str) -> Vec<String> {
+        let mut ranges = Vec::new();
+
+        if let Some(ref meta) = self.metadata {
+            for iface in &meta.instance.network_interfaces {
+                if iface.mac.eq_ignore_ascii_case(mac) {
+                    for alias in &iface.ip_aliases {
+                        let range = if alias.contains('/') {
+                            alias.trim().to_string()
+                        } else {
+                            format!("{}/32", alias.trim())
+                        };
+
+                        match crate::parser::parse_cidr(&range) {
+                            Ok(_) => ranges.push(range),
+                            Err(e) => tracing::warn!("Ignoring invalid alias IP range='{}': {}", alias, e),
+                        }
+                    }
+                    break;
+                }
+            }
         }
 
-        let mut prefix = 0;
-        for octet in parts {
-            prefix += octet.count_ones();
+        ranges
+    }
+
+    /// Load balancer addresses (internal/external forwarding rules and
+    /// target instances) that must be accepted locally on the interface
+    fn parse_forwarded_ips_from_metadata_by_mac(&self, mac: &str) -> Vec<String> {
+        let mut forwarded = Vec::new();
+
+        if let Some(ref meta) = self.metadata {
+            for iface in &meta.instance.network_interfaces {
+                if iface.mac.eq_ignore_ascii_case(mac) {
+                    let ips = iface
+                        .forwarded_ips
+                        .iter()
+                        .chain(&iface.forwarded_ipv6s)
+                        .chain(&iface.target_instance_ips);
+
+                    for ip in ips {
+                        let ip = ip.trim();
+                        let range = if ip.contains('/') {
+                            ip.to_string()
+                        } else if ip.contains(':') {
+                            format!("{}/128", ip)
+                        } else {
+                            format!("{}/32", ip)
+                        };
+
+                        match crate::parser::parse_cidr(&range) {
+                            Ok(_) => forwarded.push(range),
+                            Err(e) => tracing::warn!("Ignoring invalid forwarded IP='{}': {}", ip, e),
+                        }
+                    }
+                    break;
+                }
+            }
         }
-        prefix as u8
+
+        forwarded
+    }
+
+    /// Splits alias ranges into addresses to assign and prefixes to install
+    /// as local routes, according to the ip_aliases mode
+    fn parse_alias_ranges(&self, mac: &str, prefix: u8) -> (HashMap<String, bool>, HashMap<String, bool>) {
+        let mut addresses = HashMap::new();
+        let mut prefixes = HashMap::new();
+
+        for range in self.parse_alias_ranges_from_metadata_by_mac(mac) {
+            if self.ip_aliases != "address" {
+                prefixes.insert(range, true);
+                continue;
+            }
+
+            match crate::parser::expand_ipv4_prefix(&range) {
+                Ok(ips) => {
+                    for ip in ips {
+                        addresses.insert(format!("{}/{}", ip, prefix), true);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Alias IP range='{}' can not be assigned as addresses, installing local route: {}", range, e);
+                    prefixes.insert(range, true);
+                }
+            }
+        }
+
+        (addresses, prefixes)
+    }
+
+    async fn fetch_metadata_text(&self, path: &str) -> Result<String> {
+        self.client.get_text(path).await
+    }
+
+    async fn fetch_metadata_json<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.client.get_json::<T>(path).await
+    }
+
+    /// Fetches only the keys we use instead of the whole tree, which can be
+    /// large (ssh keys, startup scripts, custom attributes)
+    async fn fetch_metadata_by_key(&self) -> Result<GCPMetadata> {
+        let instance = GCPInstance {
+            id: self.fetch_metadata_text("instance/id").await?,
+            hostname: self.fetch_metadata_text("instance/hostname").await?,
+            machine_type: self.fetch_metadata_text("instance/machine-type").await?,
+            network_interfaces: self
+                .fetch_metadata_json("instance/network-interfaces/?recursive=true")
+                .await?,
+        };
+
+        let project = GCPProject {
+            project_id: self.fetch_metadata_text("project/project-id").await?,
+            numeric_project_id: self
+                .fetch_metadata_text("project/numeric-project-id")
+                .await?
+                .parse()
+                .context("Invalid numeric-project-id")?,
+        };
+
+        Ok(GCPMetadata { instance, project })
+    }
+
+    fn subnet_mask_to_cidr(&self, mask: &str) -> u8 {
+        crate::parser::parse_netmask(mask).unwrap_or(24)
     }
 }
 
 #[async_trait::async_trait]
 impl super::CloudProvider for GCP {
     async fn fetch_cloud_metadata(&mut self) -> Result<()> {
-        let client = reqwest::Client::new();
-        let response = client
-            .get(GCP_METADATA_ENDPOINT)
-            .header("Metadata-Flavor", "Google")
-            .send()
-            .await?;
-
-        self.metadata = Some(response.json::<GCPMetadata>().await?);
+        let metadata = if self.recursive {
+            self.fetch_metadata_json::<GCPMetadata>("?recursive=true").await?
+        } else {
+            self.fetch_metadata_by_key().await?
+        };
+
+        self.metadata = Some(metadata);
         Ok(())
     }
 
     async fn configure_network_from_cloud_meta(&self, env: &mut super::Environment) -> Result<()> {
-        for (mac, link) in &env.links.links_by_mac {
-            let addresses = self.parse_ipv4_addresses_from_metadata_by_mac(mac);
-            if !addresses.is_empty() {
-                let gateway = self.parse_ipv4_gateway_from_metadata_by_mac(mac);
-                let mtu = self.parse_link_mtu_from_metadata_by_mac(mac);
+        let links: Vec<Link> = env.links.links_by_mac.values().cloned().collect();
+        for link in &links {
+            self.configure_link_from_cloud_meta(env, link).await?;
+        }
+        Ok(())
+    }
 
-                super::network::configure_network(env, link, addresses, gateway, mtu).await?;
-            }
+    async fn configure_link_from_cloud_meta(&self, env: &mut super::Environment, link: &Link) -> Result<()> {
+        let mut addresses = self.parse_ipv4_addresses_from_metadata_by_mac(&link.mac);
+        if addresses.is_empty() {
+            return Ok(());
         }
+
+        let prefix = self
+            .parse_subnet_mask_from_metadata_by_mac(&link.mac)
+            .map(|mask| self.subnet_mask_to_cidr(&mask))
+            .unwrap_or(24);
+        let (alias_addresses, mut prefixes) = self.parse_alias_ranges(&link.mac, prefix);
+        addresses.extend(alias_addresses);
+
+        for forwarded in self.parse_forwarded_ips_from_metadata_by_mac(&link.mac) {
+            prefixes.insert(forwarded, true);
+        }
+
+        let gateway = self.parse_ipv4_gateway_from_metadata_by_mac(&link.mac);
+        let gateway6 = self.parse_ipv6_gateway_from_metadata_by_mac(&link.mac);
+        let mtu = self.parse_link_mtu_from_metadata_by_mac(&link.mac);
+
+        super::network::configure_network(env, link, addresses, prefixes, gateway, gateway6, mtu).await?;
+
         Ok(())
     }
 
@@ -154,7 +343,7 @@ impl super::CloudProvider for GCP {
     async fn link_save_cloud_metadata(&self, env: &super::Environment) -> Result<()> {
         if let Some(ref meta) = self.metadata {
             for iface in &meta.instance.network_interfaces {
-                if let Some(link) = env.links.links_by_mac.get(&iface.mac) {
+                if let Some(link) = env.links.links_by_mac.get(&iface.mac.to_lowercase()) {
                     let path = format!("{}/{}", crate::conf::LINK_STATE_DIR, link.name);
                     crate::system::create_and_save_json(&path, iface)?;
                 }
@@ -162,4 +351,172 @@ impl super::CloudProvider for GCP {
         }
         Ok(())
     }
+
+    fn system_metadata(&self) -> Option<serde_json::Value> {
+        let meta = self.metadata.as_ref()?;
+        serde_json::to_value(&meta.instance).ok()
+    }
+
+    fn link_metadata(&self, link: &Link) -> Option<serde_json::Value> {
+        let meta = self.metadata.as_ref()?;
+        let iface = meta.instance.network_interfaces.iter().find(|iface| iface.mac.eq_ignore_ascii_case(&link.mac))?;
+        serde_json::to_value(iface).ok()
+    }
+}
+
+/// Long-polls the network interfaces subtree using wait_for_change and the
+/// ETag of the last response, notifying `refresh` whenever it changes.
+/// Without an ETag there is nothing to wait on, so the watch falls back to
+/// polling every refresh interval, following changes made on reload.
+pub async fn watch_metadata(client: MetadataClient, refresh: Arc<Notify>, mut refresh_interval: watch::Receiver<Duration>) {
+    // Failures are retried by the loop itself
+    let client = client.without_retry();
+    let timeout = Duration::from_secs(GCP_WAIT_FOR_CHANGE_TIMEOUT + 10);
+
+    tracing::info!("GCP metadata watching started");
+
+    let mut last_etag: Option<String> = None;
+    let mut retry_delay = GCP_WATCH_RETRY_DELAY.min(*refresh_interval.borrow());
+
+    loop {
+        let interval = *refresh_interval.borrow();
+        let mut path = "instance/network-interfaces/?recursive=true".to_string();
+
+        // Without an ETag the server would wait for the next change, so the
+        // first request only records the current one
+        if let Some(ref etag) = last_etag {
+            path.push_str(&format!(
+                "&wait_for_change=true&timeout_sec={}&last_etag={}",
+                GCP_WAIT_FOR_CHANGE_TIMEOUT, etag
+            ));
+        }
+
+        let delay = match client.get_response(&path, timeout).await {
+            Ok(response) => {
+                let etag = response
+                    .headers()
+                    .get("ETag")
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string());
+
+                retry_delay = GCP_WATCH_RETRY_DELAY.min(interval);
+
+                if etag.is_none() {
+                    // The next request would return right away, do not spin on it
+                    tracing::warn!("GCP metadata response has no ETag, polling again in {:?}", interval);
+                    last_etag = None;
+                    interval
+                } else {
+                    if last_etag.is_some() && etag != last_etag {
+                        tracing::info!("GCP network metadata changed, triggering refresh");
+                        refresh.notify_one();
+                    }
+
+                    last_etag = etag;
+                    continue;
+                }
+            }
+            Err(e) => {
+                tracing::warn!("GCP metadata watch request failed, retrying in {:?}: {}", retry_delay, e);
+                let delay = retry_delay;
+                retry_delay = retry_delay.saturating_mul(2).min(interval);
+                delay
+            }
+        };
+
+        // A new refresh interval applies right away
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            Ok(()) = refresh_interval.changed() => {
+                retry_delay = GCP_WATCH_RETRY_DELAY.min(*refresh_interval.borrow());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As served by /computeMetadata/v1/?recursive=true, trimmed to what we use
+    const METADATA: &str = r#"{
+        "instance": {
+            "id": "4567890123456789012",
+            "hostname": "vm-1.c.project.internal",
+            "machineType": "projects/123/machineTypes/e2-standard-2",
+            "networkInterfaces": [{
+                "mac": "42:01:0a:80:00:02",
+                "ip": "10.128.0.2",
+                "subnetmask": "255.255.240.0",
+                "gateway": "10.128.0.1",
+                "mtu": 1460,
+                "ipAliases": ["10.1.2.0/24", "10.1.3.5"],
+                "forwardedIps": ["34.120.0.10"],
+                "forwardedIpv6s": ["2600:1900:4000:1::/96"],
+                "targetInstanceIps": ["35.200.0.5"]
+            }]
+        },
+        "project": {
+            "projectId": "project",
+            "numericProjectId": 123
+        }
+    }"#;
+
+    fn gcp(ip_aliases: &str) -> GCP {
+        let config = crate::conf::GcpCloudConfig {
+            ip_aliases: ip_aliases.to_string(),
+            ..Default::default()
+        };
+        let mut gcp = GCP::new(&config, HttpClient::default());
+        gcp.metadata = Some(serde_json::from_str(METADATA).unwrap());
+        gcp
+    }
+
+    #[test]
+    fn test_alias_ranges_keep_their_prefix() {
+        let gcp = gcp("route");
+
+        // Ranges used to be formatted as alias/prefix, e.g. 10.1.2.0/24/32
+        assert_eq!(
+            gcp.parse_alias_ranges_from_metadata_by_mac("42:01:0A:80:00:02"),
+            vec!["10.1.2.0/24", "10.1.3.5/32"]
+        );
+        assert!(gcp.parse_alias_ranges_from_metadata_by_mac("42:01:0a:80:00:03").is_empty());
+    }
+
+    #[test]
+    fn test_ip_aliases_route() {
+        let gcp = gcp("route");
+        let (addresses, prefixes) = gcp.parse_alias_ranges("42:01:0a:80:00:02", 20);
+
+        assert!(addresses.is_empty());
+        assert_eq!(prefixes.len(), 2);
+        assert!(prefixes.contains_key("10.1.2.0/24"));
+        assert!(prefixes.contains_key("10.1.3.5/32"));
+    }
+
+    #[test]
+    fn test_ip_aliases_address() {
+        let gcp = gcp("address");
+        let (addresses, prefixes) = gcp.parse_alias_ranges("42:01:0a:80:00:02", 20);
+
+        // Every address of the range, with the subnet's prefix length
+        assert_eq!(addresses.len(), 257);
+        assert!(addresses.contains_key("10.1.2.0/20"));
+        assert!(addresses.contains_key("10.1.2.255/20"));
+        assert!(addresses.contains_key("10.1.3.5/20"));
+        assert!(prefixes.is_empty());
+    }
+
+    #[test]
+    fn test_forwarded_ips() {
+        let gcp = gcp("route");
+
+        // Single addresses become host routes of their family
+        assert_eq!(
+            gcp.parse_forwarded_ips_from_metadata_by_mac("42:01:0a:80:00:02"),
+            vec!["34.120.0.10/32", "2600:1900:4000:1::/96", "35.200.0.5/32"]
+        );
+        assert!(gcp.parse_forwarded_ips_from_metadata_by_mac("42:01:0a:80:00:03").is_empty());
+    }
 }
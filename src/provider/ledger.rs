@@ -0,0 +1,332 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use crate::network::{self, Link, LocalRoute, Route, RoutingPolicyRule};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+const LEDGER_FILE: &str = "ledger.json";
+
+/// Everything the daemon installed for one link
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkLedger {
+    pub if_index: u32,
+    pub addresses: HashMap<String, bool>,
+    pub prefixes: HashMap<String, bool>,
+    pub routes: Vec<Route>,
+    pub local_routes: Vec<LocalRoute>,
+    pub rules: Vec<RoutingPolicyRule>,
+}
+
+/// Managed objects keyed by MAC, persisted so a restarted daemon knows what
+/// it installed before
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Ledger {
+    pub route_table: u32,
+    pub links: HashMap<String, LinkLedger>,
+}
+
+impl LinkLedger {
+    pub fn from_environment(env: &super::Environment, link: &Link) -> Self {
+        let addresses = env.addresses_by_mac.get(&link.mac).cloned().unwrap_or_default();
+        let prefixes = env.prefixes_by_mac.get(&link.mac).cloned().unwrap_or_default();
+
+        let routes: Vec<Route> = env
+            .routes_by_index
+            .get(&link.ifindex)
+            .into_iter()
+            .chain(env.ipv6_routes_by_index.get(&link.ifindex))
+            .cloned()
+            .collect();
+
+        let local_routes: Vec<LocalRoute> = env
+            .local_routes_by_prefix
+            .values()
+            .filter(|route| route.if_index == link.ifindex)
+            .cloned()
+            .collect();
+
+        let table = env.link_table(link.ifindex);
+        let rules: Vec<RoutingPolicyRule> = env
+            .routing_rules_by_address_from
+            .values()
+            .chain(env.routing_rules_by_address_to.values())
+            .filter(|rule| rule.table == table)
+            .cloned()
+            .collect();
+
+        Self {
+            if_index: link.ifindex,
+            addresses,
+            prefixes,
+            routes,
+            local_routes,
+            rules,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+            && self.prefixes.is_empty()
+            && self.routes.is_empty()
+            && self.local_routes.is_empty()
+            && self.rules.is_empty()
+    }
+}
+
+impl Ledger {
+    pub fn from_environment(env: &super::Environment) -> Self {
+        let links = env
+            .links
+            .links_by_mac
+            .values()
+            .map(|link| (link.mac.clone(), LinkLedger::from_environment(env, link)))
+            .filter(|(_, link)| !link.is_empty())
+            .collect();
+
+        Self {
+            route_table: env.route_table,
+            links,
+        }
+    }
+
+    /// Puts the link's objects back into the environment maps
+    fn restore_link(env: &mut super::Environment, mac: &str, link: LinkLedger) {
+        env.addresses_by_mac.insert(mac.to_string(), link.addresses);
+        env.prefixes_by_mac.insert(mac.to_string(), link.prefixes);
+
+        for route in link.routes {
+            if route.gw.contains(':') {
+                env.ipv6_routes_by_index.insert(route.if_index, route);
+            } else {
+                env.routes_by_index.insert(route.if_index, route);
+            }
+        }
+
+        for route in link.local_routes {
+            env.local_routes_by_prefix.insert(route.destination.clone(), route);
+        }
+
+        for rule in link.rules {
+            if let Some(ref from) = rule.from {
+                env.routing_rules_by_address_from.insert(from.clone(), rule);
+            } else if let Some(ref to) = rule.to {
+                env.routing_rules_by_address_to.insert(to.clone(), rule);
+            }
+        }
+    }
+
+    pub fn load(dir: &str) -> Result<Option<Self>> {
+        let path = Path::new(dir).join(LEDGER_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read '{}'", path.display()))?;
+        let ledger = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse '{}'", path.display()))?;
+
+        Ok(Some(ledger))
+    }
+
+    pub fn save(&self, dir: &str) -> Result<()> {
+        let path = Path::new(dir).join(LEDGER_FILE);
+        crate::system::create_and_save_json(&path.to_string_lossy(), self)
+    }
+}
+
+pub fn save_ledger(env: &super::Environment) {
+    if let Err(e) = Ledger::from_environment(env).save(&env.state_dir) {
+        tracing::warn!("Failed to save state ledger: {}", e);
+    }
+}
+
+/// Hands the objects of links that still exist with the same index back to
+/// the environment, returning those of links that are gone
+fn restore_links(env: &mut super::Environment, links: HashMap<String, LinkLedger>) -> Vec<(String, LinkLedger)> {
+    let mut stale = Vec::new();
+
+    for (mac, link) in links {
+        match env.links.links_by_mac.get(&mac) {
+            Some(current) if current.ifindex == link.if_index => {
+                tracing::debug!(
+                    "Restored state of link='{}' ifindex='{}': {} addresses, {} rules",
+                    current.name,
+                    current.ifindex,
+                    link.addresses.len(),
+                    link.rules.len()
+                );
+                Ledger::restore_link(env, &mac, link);
+            }
+            _ => stale.push((mac, link)),
+        }
+    }
+
+    stale
+}
+
+/// Reloads the ledger written by a previous run. The objects are handed to
+/// the environment so the next configuration pass removes whatever vanished
+/// from metadata, and teardown and migration know about them. Objects of
+/// links that are gone are removed right away unless stale cleanup is
+/// disabled. The routing table base is migrated if it changed in between.
+pub async fn restore_ledger(env: &mut super::Environment, cleanup_stale: bool) -> Result<()> {
+    let ledger = match Ledger::load(&env.state_dir)? {
+        Some(ledger) => ledger,
+        None => return Ok(()),
+    };
+
+    let nl = env.netlink.clone();
+    env.links = network::acquire_links(&nl).await?;
+
+    let table_base = env.route_table;
+    env.route_table = ledger.route_table;
+
+    for (mac, link) in restore_links(env, ledger.links) {
+        if !cleanup_stale {
+            tracing::info!(
+                "Link mac='{}' ifindex='{}' is gone, stale cleanup disabled, keeping its rules",
+                mac, link.if_index
+            );
+            continue;
+        }
+
+        // The kernel dropped addresses and routes with the link, policy
+        // rules and local routes stay behind
+        tracing::info!("Link mac='{}' ifindex='{}' is gone, removing its stale rules", mac, link.if_index);
+
+        for rule in &link.rules {
+            if let Err(e) = network::routing_policy_rule_remove(&nl, rule).await {
+                tracing::warn!("Failed to remove stale routing policy rule table='{}': {}", rule.table, e);
+            }
+        }
+
+        for route in &link.local_routes {
+            if let Err(e) = network::local_route_remove(&nl, route).await {
+                tracing::warn!("Failed to remove stale local route prefix='{}': {}", route.destination, e);
+            }
+        }
+    }
+
+    let result = super::migrate_route_table(env, table_base).await;
+    if result.is_err() {
+        // Run on the configured base regardless, what could not be removed
+        // from the old tables stays tracked for teardown
+        env.route_table = table_base;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud::CloudProvider as CloudKind;
+
+    #[test]
+    fn test_ledger_roundtrip() {
+        let config = crate::conf::Config::default();
+        let mut env = super::super::Environment::new(CloudKind::AWS, &config).unwrap();
+
+        let link = Link {
+            name: "eth1".to_string(),
+            ifindex: 3,
+            oper_state: "Up".to_string(),
+            mac: "02:00:00:00:00:01".to_string(),
+            mtu: 9001,
+            addresses: None,
+            driver: "ena".to_string(),
+            kind: None,
+            master: None,
+        };
+        env.links.insert(link);
+
+        let table = env.route_table + 3;
+        env.addresses_by_mac.insert(
+            "02:00:00:00:00:01".to_string(),
+            HashMap::from([("10.0.1.10/24".to_string(), true), ("10.0.1.11/24".to_string(), false)]),
+        );
+        env.routes_by_index.insert(3, Route { table, if_index: 3, gw: "10.0.1.1".to_string() });
+        env.routing_rules_by_address_from.insert(
+            "10.0.1.10".to_string(),
+            RoutingPolicyRule { from: Some("10.0.1.10".to_string()), to: None, table },
+        );
+        env.routing_rules_by_address_to.insert(
+            "10.0.1.10".to_string(),
+            RoutingPolicyRule { from: None, to: Some("10.0.1.10".to_string()), table },
+        );
+
+        let ledger = Ledger::from_environment(&env);
+        let json = serde_json::to_string(&ledger).unwrap();
+        let parsed: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, ledger);
+
+        let mut restored = super::super::Environment::new(CloudKind::AWS, &config).unwrap();
+        for (mac, link) in parsed.links {
+            Ledger::restore_link(&mut restored, &mac, link);
+        }
+
+        assert_eq!(restored.addresses_by_mac, env.addresses_by_mac);
+        assert_eq!(restored.routes_by_index.get(&3), env.routes_by_index.get(&3));
+        assert_eq!(restored.routing_rules_by_address_from.len(), 1);
+        assert_eq!(restored.routing_rules_by_address_to.len(), 1);
+    }
+
+    #[test]
+    fn test_restore_links_returns_vanished_links() {
+        let config = crate::conf::Config::default();
+        let mut env = super::super::Environment::new(CloudKind::AWS, &config).unwrap();
+
+        env.links.insert(Link {
+            name: "eth1".to_string(),
+            ifindex: 3,
+            oper_state: "Up".to_string(),
+            mac: "02:00:00:00:00:01".to_string(),
+            mtu: 9001,
+            addresses: None,
+            driver: "ena".to_string(),
+            kind: None,
+            master: None,
+        });
+
+        let table = env.route_table + 3;
+        let present = LinkLedger {
+            if_index: 3,
+            addresses: HashMap::from([("10.0.1.10/24".to_string(), true)]),
+            rules: vec![RoutingPolicyRule { from: Some("10.0.1.10".to_string()), to: None, table }],
+            ..Default::default()
+        };
+        let reindexed = LinkLedger {
+            if_index: 4,
+            addresses: HashMap::from([("10.0.2.10/24".to_string(), true)]),
+            ..Default::default()
+        };
+
+        let stale = restore_links(
+            &mut env,
+            HashMap::from([
+                ("02:00:00:00:00:01".to_string(), present.clone()),
+                ("02:00:00:00:00:02".to_string(), reindexed.clone()),
+            ]),
+        );
+
+        assert_eq!(stale, vec![("02:00:00:00:00:02".to_string(), reindexed)]);
+        assert_eq!(env.addresses_by_mac.get("02:00:00:00:00:01"), Some(&present.addresses));
+        assert_eq!(env.routing_rules_by_address_from.len(), 1);
+        assert!(!env.addresses_by_mac.contains_key("02:00:00:00:00:02"));
+    }
+
+    #[test]
+    fn test_link_ledger_with_local_routes_is_not_empty() {
+        let link = LinkLedger {
+            if_index: 3,
+            local_routes: vec![LocalRoute { destination: "10.0.2.0/28".to_string(), if_index: 3 }],
+            ..Default::default()
+        };
+
+        assert!(!link.is_empty());
+        assert!(LinkLedger::default().is_empty());
+    }
+}
@@ -1,50 +1,89 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+mod alibaba;
 mod azure;
+mod digitalocean;
 mod ec2;
 mod gcp;
+mod ledger;
 mod network;
+mod oracle;
+mod reconcile;
+mod status;
 mod watch;
 
+pub use alibaba::*;
 pub use azure::*;
+pub use digitalocean::*;
 pub use ec2::*;
 pub use gcp::*;
+pub use ledger::*;
 pub use network::*;
+pub use oracle::*;
+pub use reconcile::*;
+pub use status::*;
 pub use watch::*;
 
 use crate::cloud::CloudProvider as CloudKind;
-use crate::network::{Links, Route, RoutingPolicyRule};
+use crate::network::{Link, Links, LocalRoute, NetlinkContext, Route, RoutingPolicyRule};
 use anyhow::Result;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 #[async_trait::async_trait]
 pub trait CloudProvider: Send + Sync {
     async fn fetch_cloud_metadata(&mut self) -> Result<()>;
     async fn configure_network_from_cloud_meta(&self, env: &mut Environment) -> Result<()>;
+    async fn configure_link_from_cloud_meta(&self, env: &mut Environment, link: &Link) -> Result<()>;
     async fn save_cloud_metadata(&self) -> Result<()>;
     async fn link_save_cloud_metadata(&self, env: &Environment) -> Result<()>;
+    /// Instance metadata, as saved to the system state directory
+    fn system_metadata(&self) -> Option<serde_json::Value>;
+    /// Metadata of one link, as saved to the link state directory
+    fn link_metadata(&self, link: &Link) -> Option<serde_json::Value>;
 }
 
 pub struct Environment {
     pub kind: CloudKind,
     pub provider: Box<dyn CloudProvider>,
     pub links: Links,
+    /// Netlink connection shared by every kernel request
+    pub netlink: NetlinkContext,
     pub route_table: u32,
+    pub ipv6: bool,
+    /// Addresses from metadata per MAC, true when the daemon added the
+    /// address and false when it was already there (e.g. DHCP primary)
     pub addresses_by_mac: HashMap<String, HashMap<String, bool>>,
+    pub prefixes_by_mac: HashMap<String, HashMap<String, bool>>,
     pub routes_by_index: HashMap<u32, Route>,
+    pub ipv6_routes_by_index: HashMap<u32, Route>,
+    pub local_routes_by_prefix: HashMap<String, LocalRoute>,
     pub routing_rules_by_address_from: HashMap<String, RoutingPolicyRule>,
     pub routing_rules_by_address_to: HashMap<String, RoutingPolicyRule>,
+    pub state_dir: String,
+    /// Compute diffs without touching the kernel
+    pub dry_run: bool,
+    /// Changes of the most recent configuration pass
+    pub last_diff: Diff,
+    /// Changes are computed but not applied while paused
+    pub paused: bool,
+    /// Names of links excluded from management
+    pub unmanaged: HashSet<String>,
     pub mutex: Arc<Mutex<()>>,
 }
 
 impl Environment {
     pub fn new(kind: CloudKind, config: &crate::conf::Config) -> Option<Self> {
+        let client = crate::web::HttpClient::from_config(config).with_metrics_provider(kind.as_str());
         let provider: Box<dyn CloudProvider> = match kind {
-            CloudKind::Azure => Box::new(Azure::new(&config.cloud.azure)),
-            CloudKind::AWS => Box::new(EC2::new(&config.cloud.aws)),
-            CloudKind::GCP => Box::new(GCP::new(&config.cloud.gcp)),
+            CloudKind::Azure => Box::new(Azure::new(&config.cloud.azure, client)),
+            CloudKind::AWS => Box::new(EC2::new(&config.cloud.aws, client)),
+            CloudKind::GCP => Box::new(GCP::new(&config.cloud.gcp, client)),
+            CloudKind::Alibaba => Box::new(Alibaba::new(&config.cloud.alibaba, client)),
+            CloudKind::Oracle => Box::new(Oracle::new(&config.cloud.oracle, client)),
+            CloudKind::DigitalOcean => Box::new(DigitalOcean::new(&config.cloud.digitalocean, client)),
             _ => return None,
         };
 
@@ -52,28 +91,146 @@ impl Environment {
             kind,
             provider,
             links: Links::new(),
+            netlink: NetlinkContext::new(),
             route_table: config.network.routing.table_base,
+            ipv6: config.features.ipv6,
             addresses_by_mac: HashMap::new(),
+            prefixes_by_mac: HashMap::new(),
             routes_by_index: HashMap::new(),
+            ipv6_routes_by_index: HashMap::new(),
+            local_routes_by_prefix: HashMap::new(),
             routing_rules_by_address_from: HashMap::new(),
             routing_rules_by_address_to: HashMap::new(),
+            state_dir: config.state.directory.clone(),
+            dry_run: false,
+            last_diff: Diff::default(),
+            paused: false,
+            unmanaged: HashSet::new(),
             mutex: Arc::new(Mutex::new(())),
         })
     }
+
+    /// Routing table holding a link's default routes, also the table its
+    /// policy rules point at
+    pub fn link_table(&self, if_index: u32) -> u32 {
+        self.route_table + if_index
+    }
 }
 
 pub async fn acquire_cloud_metadata(env: &mut Environment) -> Result<()> {
     let _lock = env.mutex.lock().unwrap();
 
-    env.links = crate::network::acquire_links().await?;
-    env.provider.fetch_cloud_metadata().await?;
+    // Every pass starts from a fresh dump
+    env.netlink.invalidate_links();
+    env.links = crate::network::acquire_links(&env.netlink).await?;
 
-    Ok(())
+    let start = Instant::now();
+    let result = env.provider.fetch_cloud_metadata().await;
+    crate::metrics::METRICS.observe_metadata_fetch(env.kind.as_str(), start.elapsed(), result.is_ok());
+
+    result
 }
 
 pub async fn configure_network_metadata(env: &mut Environment) -> Result<()> {
     let _lock = env.mutex.lock().unwrap();
-    env.provider.configure_network_from_cloud_meta(env).await
+    env.last_diff = Diff::default();
+    let start = Instant::now();
+    let result = env.provider.configure_network_from_cloud_meta(env).await;
+    env.netlink.invalidate_links();
+    observe_reconcile(env, start, result.is_ok());
+    save_ledger(env);
+    result
+}
+
+/// Callers serialize passes by holding the daemon's lock on the environment
+pub async fn configure_link_metadata(env: &mut Environment, link: &Link) -> Result<()> {
+    env.last_diff = Diff::default();
+    let start = Instant::now();
+    let result = env.provider.configure_link_from_cloud_meta(env, link).await;
+    env.netlink.invalidate_links();
+    observe_reconcile(env, start, result.is_ok());
+    save_ledger(env);
+    result
+}
+
+/// Records the pass duration and result, and what is managed per link after it
+fn observe_reconcile(env: &Environment, start: Instant, ok: bool) {
+    let metrics = &crate::metrics::METRICS;
+    // Nothing reaches the kernel while paused, that is no successful pass
+    metrics.observe_reconcile(start.elapsed(), ok, !env.paused && !env.dry_run);
+
+    let mut counts = Vec::new();
+    for link in env.links.links_by_mac.values() {
+        let table = env.link_table(link.ifindex);
+
+        let addresses = env.addresses_by_mac.get(&link.mac).map_or(0, |addresses| addresses.len());
+        let routes = env.routes_by_index.contains_key(&link.ifindex) as usize
+            + env.ipv6_routes_by_index.contains_key(&link.ifindex) as usize;
+        let local_routes = env
+            .local_routes_by_prefix
+            .values()
+            .filter(|route| route.if_index == link.ifindex)
+            .count();
+        let rules = env
+            .routing_rules_by_address_from
+            .values()
+            .chain(env.routing_rules_by_address_to.values())
+            .filter(|rule| rule.table == table)
+            .count();
+
+        counts.push((link.name.clone(), "address", addresses as u64));
+        counts.push((link.name.clone(), "route", routes as u64));
+        counts.push((link.name.clone(), "local_route", local_routes as u64));
+        counts.push((link.name.clone(), "rule", rules as u64));
+    }
+
+    metrics.set_managed_objects(counts);
+}
+
+/// Re-reads links and configures one of them from the metadata fetched last
+pub async fn reconfigure_link(env: &mut Environment, name: &str) -> Result<Diff> {
+    env.links = crate::network::acquire_links(&env.netlink).await?;
+
+    let link = find_link_by_name(env, name)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Link '{}' not found", name))?;
+
+    configure_link_metadata(env, &link).await?;
+
+    Ok(env.last_diff.clone())
+}
+
+/// Leaves the link's current configuration in place but stops touching it
+/// until manage_link
+pub fn unmanage_link(env: &mut Environment, name: &str) -> Result<()> {
+    if find_link_by_name(env, name).is_none() {
+        return Err(anyhow::anyhow!("Link '{}' not found", name));
+    }
+
+    tracing::info!("Link='{}' is no longer managed", name);
+    env.unmanaged.insert(name.to_string());
+
+    Ok(())
+}
+
+pub async fn manage_link(env: &mut Environment, name: &str) -> Result<Diff> {
+    if env.unmanaged.remove(name) {
+        tracing::info!("Link='{}' is managed again", name);
+    }
+
+    reconfigure_link(env, name).await
+}
+
+/// Computes the changes a configuration pass would make without applying them.
+/// The diff of the last applied pass is left as it was.
+pub async fn plan_network_metadata(env: &mut Environment) -> Result<Diff> {
+    let last_diff = std::mem::take(&mut env.last_diff);
+    env.dry_run = true;
+    let result = env.provider.configure_network_from_cloud_meta(env).await;
+    env.netlink.invalidate_links();
+    env.dry_run = false;
+    let plan = std::mem::replace(&mut env.last_diff, last_diff);
+    result.map(|_| plan)
 }
 
 pub async fn save_metadata(env: &Environment) -> Result<()> {
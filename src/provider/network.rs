@@ -1,170 +1,323 @@
 
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use super::reconcile::{is_ipv6_address, LinkSpec};
 use crate::network::{self, Link, Route, RoutingPolicyRule};
 use anyhow::Result;
 use std::collections::HashMap;
 
+/// Reconciles the link against its complete metadata in one pass. Prefixes
+/// are delegated or alias ranges installed as local routes, with a "from"
+/// rule covering the whole prefix pointing at the link's table.
 pub async fn configure_network(
     env: &mut super::Environment,
     link: &Link,
     new_addresses: HashMap<String, bool>,
+    new_prefixes: HashMap<String, bool>,
     gateway: Option<String>,
+    gateway6: Option<String>,
     mtu: Option<u32>,
 ) -> Result<()> {
     tracing::info!("Link='{}' ifindex='{}' configuring network ...", link.name, link.ifindex);
 
-    // Bring link up if needed
-    if link.oper_state != "Up" {
-        network::link_set_oper_state_up(link.ifindex).await?;
-    }
+    let nl = env.netlink.clone();
+
+    let addresses: Vec<String> = new_addresses
+        .into_keys()
+        .filter(|addr| env.ipv6 || !is_ipv6_address(addr))
+        .collect();
 
-    // Set MTU if different
-    if let Some(new_mtu) = mtu {
-        if link.mtu != new_mtu {
-            network::link_set_mtu(link.ifindex, new_mtu).await?;
+    let gateway = match gateway {
+        Some(gw) => Some(gw),
+        None => Some(network::get_ipv4_gateway(&nl, link.ifindex).await?),
+    };
+
+    let gateway6 = match gateway6 {
+        Some(gw) => Some(gw),
+        None if addresses.iter().any(|addr| is_ipv6_address(addr)) => {
+            match network::get_ipv6_gateway(&nl, link.ifindex).await {
+                Ok(gw) => Some(gw),
+                Err(e) => {
+                    // Router advertisement may not have arrived yet, retry on next refresh
+                    tracing::warn!(
+                        "Failed to find IPv6 gateway for link='{}' ifindex='{}': {}",
+                        link.name, link.ifindex, e
+                    );
+                    None
+                }
+            }
         }
-    }
+        None => None,
+    };
 
-    // Configure addresses
-    for (addr, _) in &new_addresses {
-        network::address_set(&link.name, addr).await?;
-        tracing::info!("Successfully added address='{}' on link='{}' ifindex='{}'",
-            addr, link.name, link.ifindex);
-    }
+    let spec = LinkSpec {
+        addresses,
+        prefixes: new_prefixes.into_keys().collect(),
+        gateway,
+        gateway6,
+        mtu,
+    };
 
-    // Configure route
-    configure_route(env, link, gateway.as_deref()).await?;
+    super::reconcile_link(env, link, &spec).await?;
 
-    // Configure routing policy rules for each address
-    for (addr, _) in &new_addresses {
-        configure_routing_policy_rule(env, link, addr).await?;
-    }
+    Ok(())
+}
 
-    // Remove old addresses that are no longer in metadata
-    if let Some(old_addrs) = env.addresses_by_mac.get(&link.mac) {
-        for (old_addr, _) in old_addrs {
-            if !new_addresses.contains_key(old_addr) {
-                remove_routing_policy_rule(env, old_addr, link).await?;
-                network::address_remove(&link.name, old_addr).await?;
-                tracing::info!("Removed address='{}' from link='{}' ifindex='{}'",
-                    old_addr, link.name, link.ifindex);
+/// Installs the default route and policy rules of the links listed in
+/// network.supplementary in their tables from the configured base. They are
+/// tracked like metadata links, so table migration, teardown and the ledger
+/// cover them.
+pub async fn configure_supplementary_links(env: &mut super::Environment, supplementary: &str) -> Result<()> {
+    let nl = env.netlink.clone();
+
+    for name in supplementary.split_whitespace() {
+        let if_index = match network::get_link_index_by_name(&nl, name).await {
+            Ok(index) => index,
+            Err(e) => {
+                tracing::debug!("Failed to find link='{}'. Ignoring...: {}", name, e);
+                continue;
             }
+        };
+
+        let result = configure_supplementary_link(env, if_index).await;
+        super::save_ledger(env);
+
+        if let Err(e) = result {
+            tracing::error!("Failed to configure network for link='{}' ifindex='{}': {}", name, if_index, e);
+            return Err(e);
         }
-    }
 
-    // Update environment state
-    env.addresses_by_mac.insert(link.mac.clone(), new_addresses);
+        tracing::debug!("Successfully configured network for link='{}' ifindex='{}'", name, if_index);
+    }
 
     Ok(())
 }
 
-async fn configure_route(
-    env: &mut super::Environment,
-    link: &Link,
-    gateway: Option<&str>,
-) -> Result<()> {
-    let gw = match gateway {
-        Some(gw_str) => gw_str.to_string(),
-        None => network::get_ipv4_gateway(link.ifindex).await?,
-    };
+/// Each object is tracked as soon as it is installed, so a failure halfway
+/// leaves nothing untracked
+async fn configure_supplementary_link(env: &mut super::Environment, if_index: u32) -> Result<()> {
+    let nl = env.netlink.clone();
+    let (route, rules) = network::supplementary_link_objects(&nl, if_index, env.link_table(if_index)).await?;
 
-    let table = (env.route_table + link.ifindex + link.ifindex) as u32;
+    network::route_add(&nl, &route).await?;
+    env.routes_by_index.insert(if_index, route);
 
-    let route = Route {
-        table,
-        if_index: link.ifindex,
-        gw: gw.clone(),
-    };
+    // Policy routing only makes sense with more than one interface
+    if env.links.links_by_mac.len() < 2 {
+        return Ok(());
+    }
 
-    network::route_add(&route).await?;
-    env.routes_by_index.insert(link.ifindex, route);
+    for rule in rules {
+        network::routing_policy_rule_add(&nl, &rule).await?;
 
-    tracing::info!(
-        "Successfully added default gateway='{}' for link='{}' ifindex='{}' table='{}'",
-        gw, link.name, link.ifindex, table
-    );
+        if let Some(ref from) = rule.from {
+            env.routing_rules_by_address_from.insert(from.clone(), rule);
+        } else if let Some(ref to) = rule.to {
+            env.routing_rules_by_address_to.insert(to.clone(), rule);
+        }
+    }
 
     Ok(())
 }
 
-async fn configure_routing_policy_rule(
-    env: &mut super::Environment,
-    link: &Link,
-    address: &str,
-) -> Result<()> {
-    let ip_str = address.split('/').next().unwrap_or(address);
-    let table = (env.route_table + link.ifindex) as u32;
-
-    // Add "from" rule
-    let from_rule = RoutingPolicyRule {
-        from: Some(ip_str.to_string()),
-        to: None,
-        table,
-    };
-
-    network::routing_policy_rule_add(&from_rule).await?;
-    env.routing_rules_by_address_from.insert(ip_str.to_string(), from_rule.clone());
+/// Removes the default route and policy rules of supplementary links no
+/// longer listed. Objects that could not be removed stay tracked.
+pub async fn remove_supplementary_links(env: &mut super::Environment, names: &[&str]) -> Result<()> {
+    let nl = env.netlink.clone();
+    let mut failed = 0;
+
+    for name in names {
+        let if_index = match network::get_link_index_by_name(&nl, name).await {
+            Ok(index) => index,
+            Err(e) => {
+                tracing::debug!("Failed to find link='{}'. Ignoring...: {}", name, e);
+                continue;
+            }
+        };
+
+        let table = env.link_table(if_index);
+        let rules: Vec<RoutingPolicyRule> = env
+            .routing_rules_by_address_from
+            .values()
+            .chain(env.routing_rules_by_address_to.values())
+            .filter(|rule| rule.table == table)
+            .cloned()
+            .collect();
+
+        for rule in rules {
+            match network::routing_policy_rule_remove(&nl, &rule).await {
+                Ok(()) => {
+                    env.routing_rules_by_address_from.retain(|_, r| *r != rule);
+                    env.routing_rules_by_address_to.retain(|_, r| *r != rule);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to remove routing policy rule table='{}': {}", rule.table, e);
+                    failed += 1;
+                }
+            }
+        }
 
-    tracing::info!(
-        "Successfully added routing policy rule 'from' in route table='{}' for link='{}' ifindex='{}'",
-        table, link.name, link.ifindex
-    );
+        if let Some(route) = env.routes_by_index.get(&if_index).cloned() {
+            match network::route_remove(&nl, &route).await {
+                Ok(()) => {
+                    env.routes_by_index.remove(&if_index);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to remove route gateway='{}' table='{}': {}", route.gw, route.table, e);
+                    failed += 1;
+                }
+            }
+        }
 
-    // Add "to" rule
-    let to_rule = RoutingPolicyRule {
-        from: None,
-        to: Some(ip_str.to_string()),
-        table,
-    };
+        tracing::info!("Link='{}' ifindex='{}' is no longer a supplementary link", name, if_index);
+    }
 
-    network::routing_policy_rule_add(&to_rule).await?;
-    env.routing_rules_by_address_to.insert(ip_str.to_string(), to_rule);
+    super::save_ledger(env);
 
-    tracing::info!(
-        "Successfully added routing policy rule 'to' in route table='{}' for link='{}' ifindex='{}'",
-        table, link.name, link.ifindex
-    );
+    if failed > 0 {
+        return Err(anyhow::anyhow!("Failed to remove {} rules and routes of supplementary links", failed));
+    }
 
     Ok(())
 }
 
-async fn remove_routing_policy_rule(
-    env: &mut super::Environment,
-    address: &str,
-    link: &Link,
-) -> Result<()> {
-    let ip_str = address.split('/').next().unwrap_or(address);
+/// Removes every rule, route and address the daemon added, including those
+/// of supplementary links. Failures are logged and skipped so as much as
+/// possible gets cleaned up.
+pub async fn teardown_network(env: &mut super::Environment) {
+    tracing::info!("Removing managed routing policy rules, routes and addresses ...");
+
+    let nl = env.netlink.clone();
+
+    let rules: Vec<RoutingPolicyRule> = env
+        .routing_rules_by_address_from
+        .drain()
+        .chain(env.routing_rules_by_address_to.drain())
+        .map(|(_, rule)| rule)
+        .collect();
 
-    // Remove "from" rule
-    if let Some(rule) = env.routing_rules_by_address_from.remove(ip_str) {
-        network::routing_policy_rule_remove(&rule).await?;
+    for rule in rules {
+        if let Err(e) = network::routing_policy_rule_remove(&nl, &rule).await {
+            tracing::warn!("Failed to remove routing policy rule table='{}': {}", rule.table, e);
+        }
     }
 
-    // Remove "to" rule
-    if let Some(rule) = env.routing_rules_by_address_to.remove(ip_str) {
-        network::routing_policy_rule_remove(&rule).await?;
+    let routes: Vec<Route> = env
+        .routes_by_index
+        .drain()
+        .chain(env.ipv6_routes_by_index.drain())
+        .map(|(_, route)| route)
+        .collect();
+
+    for route in routes {
+        if let Err(e) = network::route_remove(&nl, &route).await {
+            tracing::warn!("Failed to remove route gateway='{}' table='{}': {}", route.gw, route.table, e);
+        }
     }
 
-    // Remove route if no more rules for this link
-    let table = (env.route_table + link.ifindex) as u32;
-    if is_rules_by_table_empty(env, table) {
-        if let Some(route) = env.routes_by_index.remove(&link.ifindex) {
-            network::route_remove(&route).await?;
+    for (prefix, route) in env.local_routes_by_prefix.drain() {
+        if let Err(e) = network::local_route_remove(&nl, &route).await {
+            tracing::warn!("Failed to remove local route prefix='{}': {}", prefix, e);
         }
     }
 
-    Ok(())
+    env.prefixes_by_mac.clear();
+
+    for (mac, addresses) in env.addresses_by_mac.drain() {
+        let link = match env.links.links_by_mac.get(&mac) {
+            Some(link) => link,
+            None => continue,
+        };
+
+        for (addr, added) in addresses {
+            if !added {
+                continue;
+            }
+
+            match network::address_remove(&nl, &link.name, &addr).await {
+                Ok(()) => tracing::info!("Removed address='{}' from link='{}' ifindex='{}'",
+                    addr, link.name, link.ifindex),
+                Err(e) => tracing::warn!("Failed to remove address='{}' from link='{}': {}",
+                    addr, link.name, e),
+            }
+        }
+    }
+
+    super::save_ledger(env);
 }
 
-fn is_rules_by_table_empty(env: &super::Environment, table: u32) -> bool {
-    let has_from = env.routing_rules_by_address_from
-        .values()
-        .any(|rule| rule.table == table);
+/// Moves policy routing to a new table base. Rules and routes installed in
+/// the old tables are removed, the next configuration pass recreates them.
+/// Objects that could not be removed stay tracked and the old base is kept,
+/// so nothing is left behind untracked in the kernel.
+pub async fn migrate_route_table(env: &mut super::Environment, table_base: u32) -> Result<()> {
+    if env.route_table == table_base {
+        return Ok(());
+    }
+
+    tracing::info!("Migrating routing tables from base='{}' to base='{}'", env.route_table, table_base);
 
-    let has_to = env.routing_rules_by_address_to
-        .values()
-        .any(|rule| rule.table == table);
+    let nl = env.netlink.clone();
+    let mut failed = 0;
 
-    !has_from && !has_to
+    let rules: Vec<(String, RoutingPolicyRule)> = env
+        .routing_rules_by_address_from
+        .iter()
+        .map(|(key, rule)| (key.clone(), rule.clone()))
+        .collect();
+    for (key, rule) in rules {
+        match network::routing_policy_rule_remove(&nl, &rule).await {
+            Ok(()) => {
+                env.routing_rules_by_address_from.remove(&key);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to remove routing policy rule table='{}': {}", rule.table, e);
+                failed += 1;
+            }
+        }
+    }
+
+    let rules: Vec<(String, RoutingPolicyRule)> = env
+        .routing_rules_by_address_to
+        .iter()
+        .map(|(key, rule)| (key.clone(), rule.clone()))
+        .collect();
+    for (key, rule) in rules {
+        match network::routing_policy_rule_remove(&nl, &rule).await {
+            Ok(()) => {
+                env.routing_rules_by_address_to.remove(&key);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to remove routing policy rule table='{}': {}", rule.table, e);
+                failed += 1;
+            }
+        }
+    }
+
+    for routes in [&mut env.routes_by_index, &mut env.ipv6_routes_by_index] {
+        let entries: Vec<(u32, Route)> = routes.iter().map(|(if_index, route)| (*if_index, route.clone())).collect();
+        for (if_index, route) in entries {
+            match network::route_remove(&nl, &route).await {
+                Ok(()) => {
+                    routes.remove(&if_index);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to remove route gateway='{}' table='{}': {}", route.gw, route.table, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to remove {} rules and routes from the old tables, keeping base='{}'",
+            failed,
+            env.route_table
+        ));
+    }
+
+    env.route_table = table_base;
+
+    Ok(())
 }
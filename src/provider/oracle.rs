@@ -0,0 +1,217 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use crate::network::{self, Link};
+use crate::web::{HttpClient, MetadataClient};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OracleInstance {
+    pub id: String,
+    pub display_name: String,
+    pub shape: String,
+    pub region: String,
+    #[serde(default)]
+    pub availability_domain: String,
+    #[serde(default)]
+    pub compartment_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OracleVnic {
+    pub vnic_id: String,
+    pub private_ip: String,
+    pub mac_addr: String,
+    pub virtual_router_ip: String,
+    pub subnet_cidr_block: String,
+    #[serde(default)]
+    pub vlan_tag: u32,
+    #[serde(default)]
+    pub nic_index: u32,
+}
+
+pub struct Oracle {
+    instance: Option<OracleInstance>,
+    vnics: Vec<OracleVnic>,
+    client: MetadataClient,
+}
+
+impl Oracle {
+    pub fn new(config: &crate::conf::OracleCloudConfig, client: HttpClient) -> Self {
+        Self {
+            instance: None,
+            vnics: Vec::new(),
+            client: MetadataClient::new(client, &config.endpoint).with_header("Authorization", "Bearer Oracle"),
+        }
+    }
+
+    async fn fetch_metadata<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.client.get_json::<T>(path).await
+    }
+
+    fn find_vnic_by_mac(&self, mac: &str) -> Option<&OracleVnic> {
+        self.vnics.iter().find(|vnic| vnic.mac_addr.eq_ignore_ascii_case(mac))
+    }
+
+}
+
+/// Addresses of a VNIC, its private IP from metadata plus the secondary
+/// private IPs already on the link. OCI does not publish secondary IPs in the
+/// instance metadata, they are assigned out of band (e.g. by oci-utils), so
+/// they are kept as present but not added by us: they get policy routing and
+/// are never removed.
+fn parse_vnic_addresses(vnic: &OracleVnic, existing: &[String]) -> Result<HashMap<String, bool>> {
+    let (_, prefix) = crate::parser::parse_cidr(&vnic.subnet_cidr_block)
+        .with_context(|| format!("Invalid subnetCidrBlock '{}'", vnic.subnet_cidr_block))?;
+
+    let mut addresses = HashMap::new();
+    for addr in existing {
+        let ip = addr.split('/').next().unwrap_or(addr);
+        if ip != vnic.private_ip && crate::parser::cidr_contains(&vnic.subnet_cidr_block, ip) {
+            addresses.insert(format!("{}/{}", ip, prefix), false);
+        }
+    }
+
+    addresses.insert(format!("{}/{}", vnic.private_ip, prefix), true);
+
+    Ok(addresses)
+}
+
+#[async_trait::async_trait]
+impl super::CloudProvider for Oracle {
+    async fn fetch_cloud_metadata(&mut self) -> Result<()> {
+        self.instance = Some(self.fetch_metadata::<OracleInstance>("instance/").await?);
+        self.vnics = self.fetch_metadata::<Vec<OracleVnic>>("vnics/").await?;
+        Ok(())
+    }
+
+    async fn configure_network_from_cloud_meta(&self, env: &mut super::Environment) -> Result<()> {
+        let links: Vec<Link> = env.links.links_by_mac.values().cloned().collect();
+        for link in &links {
+            self.configure_link_from_cloud_meta(env, link).await?;
+        }
+        Ok(())
+    }
+
+    async fn configure_link_from_cloud_meta(&self, env: &mut super::Environment, link: &Link) -> Result<()> {
+        let vnic = match self.find_vnic_by_mac(&link.mac) {
+            Some(vnic) => vnic,
+            None => return Ok(()),
+        };
+
+        let existing: Vec<String> = network::get_ipv4_addresses(&env.netlink, &link.name)
+            .await
+            .map(|addresses| addresses.into_keys().collect())
+            .unwrap_or_default();
+
+        // A guessed prefix would install wrong rules, leave the link alone
+        let addresses = match parse_vnic_addresses(vnic, &existing) {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                tracing::warn!(
+                    "Skipping link='{}' ifindex='{}', unusable metadata: {:#}",
+                    link.name,
+                    link.ifindex,
+                    e
+                );
+                return Ok(());
+            }
+        };
+
+        let gateway = Some(vnic.virtual_router_ip.clone());
+        super::network::configure_network(env, link, addresses, HashMap::new(), gateway, None, None).await?;
+
+        Ok(())
+    }
+
+    async fn save_cloud_metadata(&self) -> Result<()> {
+        if let Some(ref instance) = self.instance {
+            let path = format!("{}/oracle", crate::conf::SYSTEM_STATE_DIR);
+            crate::system::create_and_save_json(&path, instance)?;
+        }
+        Ok(())
+    }
+
+    async fn link_save_cloud_metadata(&self, env: &super::Environment) -> Result<()> {
+        for link in env.links.links_by_mac.values() {
+            if let Some(vnic) = self.find_vnic_by_mac(&link.mac) {
+                let path = format!("{}/{}", crate::conf::LINK_STATE_DIR, link.name);
+                crate::system::create_and_save_json(&path, vnic)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn system_metadata(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(self.instance.as_ref()?).ok()
+    }
+
+    fn link_metadata(&self, link: &Link) -> Option<serde_json::Value> {
+        serde_json::to_value(self.find_vnic_by_mac(&link.mac)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As served by /opc/v2/vnics/
+    const VNICS: &str = r#"[
+        {
+            "vnicId": "ocid1.vnic.oc1.phx.aaaa",
+            "privateIp": "10.0.0.2",
+            "vlanTag": 0,
+            "macAddr": "02:00:17:00:12:34",
+            "virtualRouterIp": "10.0.0.1",
+            "subnetCidrBlock": "10.0.0.0/24",
+            "nicIndex": 0
+        },
+        {
+            "vnicId": "ocid1.vnic.oc1.phx.bbbb",
+            "privateIp": "10.0.1.2",
+            "vlanTag": 1,
+            "macAddr": "02:00:17:00:56:78",
+            "virtualRouterIp": "10.0.1.1",
+            "subnetCidrBlock": "10.0.1.0/26"
+        }
+    ]"#;
+
+    #[test]
+    fn test_parse_vnics() {
+        let vnics: Vec<OracleVnic> = serde_json::from_str(VNICS).unwrap();
+        assert_eq!(vnics.len(), 2);
+        assert_eq!(vnics[1].mac_addr, "02:00:17:00:56:78");
+        assert_eq!(vnics[1].virtual_router_ip, "10.0.1.1");
+        assert_eq!(vnics[1].vlan_tag, 1);
+        assert_eq!(vnics[1].nic_index, 0);
+
+        let addresses = parse_vnic_addresses(&vnics[1], &[]).unwrap();
+        assert_eq!(addresses, HashMap::from([("10.0.1.2/26".to_string(), true)]));
+    }
+
+    #[test]
+    fn test_secondary_addresses_are_not_owned() {
+        let vnics: Vec<OracleVnic> = serde_json::from_str(VNICS).unwrap();
+        let existing = vec![
+            "10.0.0.2/24".to_string(),
+            "10.0.0.3/24".to_string(),
+            "192.168.1.5/24".to_string(),
+        ];
+
+        let addresses = parse_vnic_addresses(&vnics[0], &existing).unwrap();
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses.get("10.0.0.2/24"), Some(&true));
+        assert_eq!(addresses.get("10.0.0.3/24"), Some(&false));
+    }
+
+    #[test]
+    fn test_invalid_subnet_cidr_block_is_rejected() {
+        let mut vnics: Vec<OracleVnic> = serde_json::from_str(VNICS).unwrap();
+        vnics[0].subnet_cidr_block = "10.0.0.0".to_string();
+
+        assert!(parse_vnic_addresses(&vnics[0], &[]).is_err());
+    }
+}
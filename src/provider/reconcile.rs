@@ -0,0 +1,652 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use crate::network::{self, Link, LocalRoute, NetlinkContext, Route, RoutingPolicyRule};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// A kernel object the daemon manages
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NetworkObject {
+    Link { name: String, if_index: u32, up: bool, mtu: u32 },
+    Address { link: String, if_index: u32, address: String },
+    Route(Route),
+    LocalRoute(LocalRoute),
+    Rule(RoutingPolicyRule),
+}
+
+impl NetworkObject {
+    /// Object kind as used in metrics labels
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NetworkObject::Link { .. } => "link",
+            NetworkObject::Address { .. } => "address",
+            NetworkObject::Route(_) => "route",
+            NetworkObject::LocalRoute(_) => "local_route",
+            NetworkObject::Rule(_) => "rule",
+        }
+    }
+}
+
+impl fmt::Display for NetworkObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkObject::Link { name, if_index, up, mtu } => {
+                write!(f, "link='{}' ifindex='{}' up='{}' mtu='{}'", name, if_index, up, mtu)
+            }
+            NetworkObject::Address { link, if_index, address } => {
+                write!(f, "address='{}' link='{}' ifindex='{}'", address, link, if_index)
+            }
+            NetworkObject::Route(route) => {
+                write!(f, "route gateway='{}' table='{}' ifindex='{}'", route.gw, route.table, route.if_index)
+            }
+            NetworkObject::LocalRoute(route) => {
+                write!(f, "local route prefix='{}' ifindex='{}'", route.destination, route.if_index)
+            }
+            NetworkObject::Rule(rule) => write!(
+                f,
+                "rule from='{}' to='{}' table='{}'",
+                rule.from.as_deref().unwrap_or("all"),
+                rule.to.as_deref().unwrap_or("all"),
+                rule.table
+            ),
+        }
+    }
+}
+
+/// One step needed to bring the kernel to the desired state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Change {
+    Add { object: NetworkObject },
+    Remove { object: NetworkObject },
+    Replace { old: NetworkObject, new: NetworkObject },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Add { object } => write!(f, "add {}", object),
+            Change::Remove { object } => write!(f, "remove {}", object),
+            Change::Replace { old, new } => write!(f, "replace {} with {}", old, new),
+        }
+    }
+}
+
+/// Ordered list of changes. Removals come before additions, and within each
+/// half objects are ordered so nothing references something not yet there
+/// (addresses before routes, routes before rules).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Diff {
+    pub changes: Vec<Change>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn extend(&mut self, other: Diff) {
+        self.changes.extend(other.changes);
+    }
+}
+
+/// Managed state of one link, either computed from metadata or dumped from
+/// the kernel
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LinkState {
+    pub up: bool,
+    pub mtu: u32,
+    pub addresses: BTreeSet<String>,
+    pub routes: Vec<Route>,
+    pub local_routes: Vec<LocalRoute>,
+    pub rules: Vec<RoutingPolicyRule>,
+}
+
+impl LinkState {
+    /// Updates the state as if the change had been made in the kernel
+    fn apply(&mut self, change: &Change) {
+        match change {
+            Change::Add { object } => self.insert(object),
+            Change::Remove { object } => self.remove(object),
+            Change::Replace { old, new } => {
+                self.remove(old);
+                self.insert(new);
+            }
+        }
+    }
+
+    fn insert(&mut self, object: &NetworkObject) {
+        match object {
+            NetworkObject::Link { up, mtu, .. } => {
+                self.up = *up;
+                self.mtu = *mtu;
+            }
+            NetworkObject::Address { address, .. } => {
+                self.addresses.insert(address.clone());
+            }
+            NetworkObject::Route(route) => self.routes.push(route.clone()),
+            NetworkObject::LocalRoute(route) => self.local_routes.push(route.clone()),
+            NetworkObject::Rule(rule) => self.rules.push(rule.clone()),
+        }
+    }
+
+    fn remove(&mut self, object: &NetworkObject) {
+        match object {
+            NetworkObject::Link { .. } => {}
+            NetworkObject::Address { address, .. } => {
+                self.addresses.remove(address);
+            }
+            NetworkObject::Route(route) => self.routes.retain(|r| r != route),
+            NetworkObject::LocalRoute(route) => self.local_routes.retain(|r| r != route),
+            NetworkObject::Rule(rule) => self.rules.retain(|r| r != rule),
+        }
+    }
+}
+
+/// Metadata input for one link
+#[derive(Debug, Clone, Default)]
+pub struct LinkSpec {
+    pub addresses: Vec<String>,
+    pub prefixes: Vec<String>,
+    pub gateway: Option<String>,
+    pub gateway6: Option<String>,
+    pub mtu: Option<u32>,
+}
+
+/// Computes the desired state of a link. Gateways must already be resolved,
+/// a missing gateway means no default route for that family.
+pub fn desired_link_state(env: &super::Environment, link: &Link, spec: &LinkSpec) -> LinkState {
+    let table = env.link_table(link.ifindex);
+
+    let addresses: BTreeSet<String> = spec
+        .addresses
+        .iter()
+        .filter(|addr| env.ipv6 || !is_ipv6_address(addr))
+        .map(|addr| canonical_cidr(addr))
+        .collect();
+
+    let prefixes: BTreeSet<String> = spec
+        .prefixes
+        .iter()
+        .filter(|prefix| env.ipv6 || !is_ipv6_address(prefix))
+        .map(|prefix| canonical_cidr(prefix))
+        .collect();
+
+    let mut routes = Vec::new();
+    if let Some(ref gw) = spec.gateway {
+        routes.push(Route { table, if_index: link.ifindex, gw: gw.clone() });
+    }
+
+    if let Some(ref gw) = spec.gateway6 {
+        if addresses.iter().any(|addr| is_ipv6_address(addr)) {
+            routes.push(Route { table, if_index: link.ifindex, gw: gw.clone() });
+        }
+    }
+
+    let local_routes = prefixes
+        .iter()
+        .map(|prefix| LocalRoute { destination: prefix.clone(), if_index: link.ifindex })
+        .collect();
+
+    // Policy routing only makes sense with more than one interface
+    let mut rules = Vec::new();
+    if env.links.links_by_mac.len() > 1 {
+        for addr in &addresses {
+            let selector = network::rule_selector(&address_ip(addr));
+            rules.push(RoutingPolicyRule { from: Some(selector.clone()), to: None, table });
+            rules.push(RoutingPolicyRule { from: None, to: Some(selector), table });
+        }
+
+        for prefix in &prefixes {
+            rules.push(RoutingPolicyRule {
+                from: Some(network::rule_selector(prefix)),
+                to: None,
+                table,
+            });
+        }
+    }
+
+    LinkState {
+        up: true,
+        mtu: spec.mtu.unwrap_or(link.mtu),
+        addresses,
+        routes,
+        local_routes,
+        rules,
+    }
+}
+
+/// Dumps what the kernel currently has for a link in the table we own
+pub async fn actual_link_state(env: &super::Environment, link: &Link) -> Result<LinkState> {
+    let mut addresses: BTreeSet<String> = network::get_ipv4_addresses(&env.netlink, &link.name).await?.into_keys().collect();
+    addresses.extend(network::get_ipv6_addresses(&env.netlink, &link.name).await.unwrap_or_default().into_keys());
+
+    Ok(LinkState {
+        up: link.oper_state == "Up",
+        mtu: link.mtu,
+        addresses,
+        routes: network::get_routes_by_table(&env.netlink, env.link_table(link.ifindex)).await?,
+        local_routes: network::get_local_routes(&env.netlink, link.ifindex).await?,
+        rules: network::get_rules_by_table(&env.netlink, env.link_table(link.ifindex)).await?,
+    })
+}
+
+/// Computes the changes turning actual into desired. Routes, local routes
+/// and rules live in tables or carry a protocol only the daemon uses, so
+/// anything not desired there is removed. Addresses share the link with
+/// DHCP and SLAAC, only previously managed ones are removed.
+pub fn diff_link_state(link: &Link, actual: &LinkState, desired: &LinkState, managed: &BTreeSet<String>) -> Diff {
+    let mut removes = Vec::new();
+    let mut adds = Vec::new();
+
+    let mut changes = Vec::new();
+    if actual.up != desired.up || actual.mtu != desired.mtu {
+        changes.push(Change::Replace {
+            old: link_object(link, actual),
+            new: link_object(link, desired),
+        });
+    }
+
+    for rule in actual.rules.iter().filter(|rule| !desired.rules.contains(rule)) {
+        removes.push(Change::Remove { object: NetworkObject::Rule(rule.clone()) });
+    }
+
+    for route in actual.local_routes.iter().filter(|route| !desired.local_routes.contains(route)) {
+        removes.push(Change::Remove { object: NetworkObject::LocalRoute(route.clone()) });
+    }
+
+    // A default route whose gateway changed is replaced in place
+    let mut replaced = Vec::new();
+    let mut route_adds = Vec::new();
+    for route in desired.routes.iter().filter(|route| !actual.routes.contains(route)) {
+        let old = actual.routes.iter().find(|old| {
+            !desired.routes.contains(old)
+                && !replaced.contains(old)
+                && is_ipv6_address(&old.gw) == is_ipv6_address(&route.gw)
+        });
+
+        match old {
+            Some(old) => {
+                replaced.push(old);
+                route_adds.push(Change::Replace {
+                    old: NetworkObject::Route(old.clone()),
+                    new: NetworkObject::Route(route.clone()),
+                });
+            }
+            None => route_adds.push(Change::Add { object: NetworkObject::Route(route.clone()) }),
+        }
+    }
+
+    for route in actual.routes.iter().filter(|route| !desired.routes.contains(route) && !replaced.contains(route)) {
+        removes.push(Change::Remove { object: NetworkObject::Route(route.clone()) });
+    }
+
+    for addr in managed.iter().filter(|addr| actual.addresses.contains(*addr) && !desired.addresses.contains(*addr)) {
+        removes.push(Change::Remove { object: address_object(link, addr) });
+    }
+
+    for addr in desired.addresses.difference(&actual.addresses) {
+        adds.push(Change::Add { object: address_object(link, addr) });
+    }
+
+    adds.extend(route_adds);
+
+    for route in desired.local_routes.iter().filter(|route| !actual.local_routes.contains(route)) {
+        adds.push(Change::Add { object: NetworkObject::LocalRoute(route.clone()) });
+    }
+
+    for rule in desired.rules.iter().filter(|rule| !actual.rules.contains(rule)) {
+        adds.push(Change::Add { object: NetworkObject::Rule(rule.clone()) });
+    }
+
+    changes.extend(removes);
+    changes.extend(adds);
+
+    Diff { changes }
+}
+
+/// Applies the changes in order, stopping at the first failure. `state` is
+/// updated with every change that went through, so on failure it tells what
+/// the kernel has now.
+pub async fn apply_diff(nl: &NetlinkContext, diff: &Diff, state: &mut LinkState) -> Result<()> {
+    let metrics = &crate::metrics::METRICS;
+
+    for change in &diff.changes {
+        match change {
+            Change::Add { object } => {
+                add_object(nl, object).await?;
+                metrics.object_added(object.kind());
+            }
+            Change::Remove { object } => {
+                remove_object(nl, object).await?;
+                metrics.object_removed(object.kind());
+            }
+            Change::Replace { old, new } => match (old, new) {
+                (NetworkObject::Link { up: was_up, mtu: old_mtu, .. }, NetworkObject::Link { if_index, up, mtu, .. }) => {
+                    if *up && !was_up {
+                        network::link_set_oper_state_up(nl, *if_index).await?;
+                    }
+
+                    if mtu != old_mtu {
+                        network::link_set_mtu(nl, *if_index, *mtu).await?;
+                    }
+                }
+                _ => {
+                    remove_object(nl, old).await?;
+                    metrics.object_removed(old.kind());
+                    add_object(nl, new).await?;
+                    metrics.object_added(new.kind());
+                }
+            },
+        }
+
+        state.apply(change);
+        tracing::info!("Applied {}", change);
+    }
+
+    Ok(())
+}
+
+async fn add_object(nl: &NetlinkContext, object: &NetworkObject) -> Result<()> {
+    match object {
+        NetworkObject::Link { if_index, .. } => network::link_set_oper_state_up(nl, *if_index).await,
+        NetworkObject::Address { if_index, address, .. } => network::address_add(nl, *if_index, address).await,
+        NetworkObject::Route(route) => network::route_add(nl, route).await,
+        NetworkObject::LocalRoute(route) => network::local_route_add(nl, route).await,
+        NetworkObject::Rule(rule) => network::routing_policy_rule_add(nl, rule).await,
+    }
+}
+
+async fn remove_object(nl: &NetlinkContext, object: &NetworkObject) -> Result<()> {
+    match object {
+        NetworkObject::Link { .. } => Ok(()),
+        NetworkObject::Address { link, address, .. } => network::address_remove(nl, link, address).await,
+        NetworkObject::Route(route) => network::route_remove(nl, route).await,
+        NetworkObject::LocalRoute(route) => network::local_route_remove(nl, route).await,
+        NetworkObject::Rule(rule) => network::routing_policy_rule_remove(nl, rule).await,
+    }
+}
+
+/// Brings one link to the state described by its metadata and records the
+/// result in the environment. With env.dry_run set or management paused the
+/// diff is only computed and collected, unmanaged links are left alone.
+pub async fn reconcile_link(env: &mut super::Environment, link: &Link, spec: &LinkSpec) -> Result<Diff> {
+    if env.unmanaged.contains(&link.name) {
+        tracing::debug!("Link='{}' ifindex='{}' is unmanaged, skipping", link.name, link.ifindex);
+        return Ok(Diff::default());
+    }
+
+    let apply = !env.dry_run && !env.paused;
+    let desired = desired_link_state(env, link, spec);
+    let actual = actual_link_state(env, link).await?;
+
+    let managed: BTreeSet<String> = env
+        .addresses_by_mac
+        .get(&link.mac)
+        .map(|addresses| addresses.keys().map(|addr| canonical_cidr(addr)).collect())
+        .unwrap_or_default();
+
+    let diff = diff_link_state(link, &actual, &desired, &managed);
+
+    if diff.is_empty() {
+        tracing::debug!("Link='{}' ifindex='{}' is up to date", link.name, link.ifindex);
+    } else if !apply {
+        for change in &diff.changes {
+            tracing::info!("Would {}", change);
+        }
+    } else {
+        let mut reached = actual.clone();
+        if let Err(e) = apply_diff(&env.netlink, &diff, &mut reached).await {
+            // Record what did get applied, otherwise addresses added so far
+            // look pre-existing next time and are never removed. Addresses
+            // neither desired nor managed (e.g. DHCP) are not ours.
+            reached
+                .addresses
+                .retain(|addr| desired.addresses.contains(addr) || managed.contains(addr));
+            record_link_state(env, link, &actual, &reached, spec);
+            env.last_diff.extend(diff);
+            return Err(e);
+        }
+    }
+
+    env.last_diff.extend(diff.clone());
+
+    if apply {
+        record_link_state(env, link, &actual, &desired, spec);
+    }
+
+    Ok(diff)
+}
+
+/// Mirrors the applied state into the environment maps, which the ledger,
+/// teardown and the network watcher work from. `actual` is the state before
+/// the pass, telling which addresses the daemon added.
+fn record_link_state(env: &mut super::Environment, link: &Link, actual: &LinkState, desired: &LinkState, spec: &LinkSpec) {
+    let previous = env.addresses_by_mac.get(&link.mac).cloned().unwrap_or_default();
+    let addresses = desired
+        .addresses
+        .iter()
+        .map(|addr| {
+            let added = previous
+                .get(addr)
+                .copied()
+                .unwrap_or_else(|| !actual.addresses.contains(addr));
+            (addr.clone(), added)
+        })
+        .collect();
+    env.addresses_by_mac.insert(link.mac.clone(), addresses);
+
+    let prefixes = spec
+        .prefixes
+        .iter()
+        .filter(|prefix| env.ipv6 || !is_ipv6_address(prefix))
+        .map(|prefix| (canonical_cidr(prefix), true))
+        .collect::<HashMap<_, _>>();
+    env.prefixes_by_mac.insert(link.mac.clone(), prefixes);
+
+    env.routes_by_index.remove(&link.ifindex);
+    env.ipv6_routes_by_index.remove(&link.ifindex);
+    for route in &desired.routes {
+        if is_ipv6_address(&route.gw) {
+            env.ipv6_routes_by_index.insert(link.ifindex, route.clone());
+        } else {
+            env.routes_by_index.insert(link.ifindex, route.clone());
+        }
+    }
+
+    env.local_routes_by_prefix.retain(|_, route| route.if_index != link.ifindex);
+    for route in &desired.local_routes {
+        env.local_routes_by_prefix.insert(route.destination.clone(), route.clone());
+    }
+
+    let table = env.link_table(link.ifindex);
+    env.routing_rules_by_address_from.retain(|_, rule| rule.table != table);
+    env.routing_rules_by_address_to.retain(|_, rule| rule.table != table);
+    for rule in &desired.rules {
+        if let Some(ref from) = rule.from {
+            env.routing_rules_by_address_from.insert(from.clone(), rule.clone());
+        } else if let Some(ref to) = rule.to {
+            env.routing_rules_by_address_to.insert(to.clone(), rule.clone());
+        }
+    }
+}
+
+fn link_object(link: &Link, state: &LinkState) -> NetworkObject {
+    NetworkObject::Link {
+        name: link.name.clone(),
+        if_index: link.ifindex,
+        up: state.up,
+        mtu: state.mtu,
+    }
+}
+
+fn address_object(link: &Link, address: &str) -> NetworkObject {
+    NetworkObject::Address {
+        link: link.name.clone(),
+        if_index: link.ifindex,
+        address: address.to_string(),
+    }
+}
+
+pub(super) fn is_ipv6_address(address: &str) -> bool {
+    address.contains(':')
+}
+
+/// Returns the IP part of a CIDR address in canonical form, so that it
+/// matches what the kernel reports back for rules
+fn address_ip(address: &str) -> String {
+    let ip_str = address.split('/').next().unwrap_or(address);
+    ip_str
+        .parse::<std::net::IpAddr>()
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|_| ip_str.to_string())
+}
+
+/// Canonical "ip/len" form, matching what address and route dumps report
+fn canonical_cidr(address: &str) -> String {
+    match address.split_once('/') {
+        Some((_, len)) => format!("{}/{}", address_ip(address), len),
+        None => address_ip(address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud::CloudProvider as CloudKind;
+
+    fn test_link(mac: &str, ifindex: u32) -> Link {
+        Link {
+            name: format!("eth{}", ifindex - 2),
+            ifindex,
+            oper_state: "Up".to_string(),
+            mac: mac.to_string(),
+            mtu: 1500,
+            addresses: None,
+            driver: "ena".to_string(),
+            kind: None,
+            master: None,
+        }
+    }
+
+    fn test_env() -> super::super::Environment {
+        let config = crate::conf::Config::default();
+        let mut env = super::super::Environment::new(CloudKind::AWS, &config).unwrap();
+        env.links.insert(test_link("02:00:00:00:00:01", 2));
+        env.links.insert(test_link("02:00:00:00:00:02", 3));
+        env
+    }
+
+    #[test]
+    fn test_diff_is_idempotent() {
+        let env = test_env();
+        let link = test_link("02:00:00:00:00:02", 3);
+        let spec = LinkSpec {
+            addresses: vec!["10.0.1.10/24".to_string(), "10.0.1.11/24".to_string()],
+            prefixes: vec!["10.0.2.0/28".to_string()],
+            gateway: Some("10.0.1.1".to_string()),
+            ..Default::default()
+        };
+
+        let desired = desired_link_state(&env, &link, &spec);
+        // Rules must point at the table holding the link's default route
+        assert!(desired.routes.iter().all(|route| route.table == env.link_table(3)));
+        assert!(desired.rules.iter().all(|rule| rule.table == env.link_table(3)));
+
+        let actual = LinkState {
+            up: true,
+            mtu: 1500,
+            addresses: BTreeSet::from(["10.0.1.10/24".to_string()]),
+            ..Default::default()
+        };
+
+        let diff = diff_link_state(&link, &actual, &desired, &BTreeSet::new());
+        // One address, one route, one local route, four address rules and one prefix rule
+        assert_eq!(diff.changes.len(), 8);
+        assert!(diff.changes.iter().all(|change| matches!(change, Change::Add { .. })));
+        assert!(matches!(
+            diff.changes[0],
+            Change::Add { object: NetworkObject::Address { ref address, .. } } if address == "10.0.1.11/24"
+        ));
+
+        // Once applied there is nothing left to do
+        assert!(diff_link_state(&link, &desired, &desired, &desired.addresses).is_empty());
+
+        // Tracking applied changes one by one ends up at the same state
+        let mut reached = actual.clone();
+        for change in &diff.changes {
+            reached.apply(change);
+        }
+        assert!(diff_link_state(&link, &reached, &desired, &desired.addresses).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unmanaged_link_is_skipped() {
+        let mut env = test_env();
+        let link = test_link("02:00:00:00:00:02", 3);
+        env.unmanaged.insert(link.name.clone());
+
+        let spec = LinkSpec {
+            addresses: vec!["10.0.1.10/24".to_string()],
+            gateway: Some("10.0.1.1".to_string()),
+            ..Default::default()
+        };
+
+        let diff = reconcile_link(&mut env, &link, &spec).await.unwrap();
+        assert!(diff.is_empty());
+        assert!(!env.addresses_by_mac.contains_key(&link.mac));
+    }
+
+    #[test]
+    fn test_diff_replace_and_remove() {
+        let env = test_env();
+        let link = test_link("02:00:00:00:00:02", 3);
+        let spec = LinkSpec {
+            addresses: vec!["10.0.1.10/24".to_string()],
+            gateway: Some("10.0.1.254".to_string()),
+            mtu: Some(9001),
+            ..Default::default()
+        };
+
+        let desired = desired_link_state(&env, &link, &spec);
+        let mut actual = desired_link_state(
+            &env,
+            &link,
+            &LinkSpec {
+                addresses: vec!["10.0.1.10/24".to_string(), "10.0.1.11/24".to_string(), "10.0.1.99/24".to_string()],
+                gateway: Some("10.0.1.1".to_string()),
+                ..Default::default()
+            },
+        );
+        actual.up = false;
+
+        // 10.0.1.99 came from DHCP, it is not ours to remove
+        let managed = BTreeSet::from(["10.0.1.10/24".to_string(), "10.0.1.11/24".to_string()]);
+        let diff = diff_link_state(&link, &actual, &desired, &managed);
+
+        assert!(matches!(
+            diff.changes[0],
+            Change::Replace { new: NetworkObject::Link { up: true, mtu: 9001, .. }, .. }
+        ));
+        assert!(diff.changes.iter().any(|change| matches!(
+            change,
+            Change::Replace { new: NetworkObject::Route(route), .. } if route.gw == "10.0.1.254"
+        )));
+        assert!(diff.changes.iter().any(|change| matches!(
+            change,
+            Change::Remove { object: NetworkObject::Address { address, .. } } if address == "10.0.1.11/24"
+        )));
+        assert!(!diff.changes.iter().any(|change| matches!(
+            change,
+            Change::Remove { object: NetworkObject::Address { address, .. } } if address == "10.0.1.99/24"
+        )));
+
+        // Rules of the removed addresses go before the addresses themselves
+        let rule = diff.changes.iter().position(|change| matches!(change, Change::Remove { object: NetworkObject::Rule(_) }));
+        let address = diff.changes.iter().position(|change| matches!(change, Change::Remove { object: NetworkObject::Address { .. } }));
+        assert!(rule < address);
+    }
+}
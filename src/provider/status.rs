@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use super::{LinkLedger, LinkState};
+use crate::network::Link;
+use anyhow::Result;
+use serde::Serialize;
+
+/// What the daemon manages on a link next to what the kernel has in the
+/// daemon's tables
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkStatus {
+    pub name: String,
+    pub if_index: u32,
+    pub mac: String,
+    pub mtu: u32,
+    pub oper_state: String,
+    pub driver: String,
+    /// Table of the link's default routes and target of its policy rules
+    pub table: u32,
+    pub managed: LinkLedger,
+    pub kernel: LinkState,
+}
+
+pub async fn link_status(env: &super::Environment, link: &Link) -> Result<LinkStatus> {
+    Ok(LinkStatus {
+        name: link.name.clone(),
+        if_index: link.ifindex,
+        mac: link.mac.clone(),
+        mtu: link.mtu,
+        oper_state: link.oper_state.clone(),
+        driver: link.driver.clone(),
+        table: env.link_table(link.ifindex),
+        managed: LinkLedger::from_environment(env, link),
+        kernel: super::actual_link_state(env, link).await?,
+    })
+}
+
+/// Status of every link, ordered by interface index
+pub async fn network_status(env: &super::Environment) -> Result<Vec<LinkStatus>> {
+    let mut links: Vec<&Link> = env.links.links_by_mac.values().collect();
+    links.sort_by_key(|link| link.ifindex);
+
+    let mut status = Vec::new();
+    for link in links {
+        status.push(link_status(env, link).await?);
+    }
+
+    Ok(status)
+}
+
+pub fn find_link_by_name<'a>(env: &'a super::Environment, name: &str) -> Option<&'a Link> {
+    env.links.links_by_mac.values().find(|link| link.name == name)
+}
@@ -1,20 +1,324 @@
-
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
+use anyhow::Result;
+use futures::stream::StreamExt;
+use netlink_packet_route::{NetlinkPayload, RtnlMessage};
+use netlink_sys::{AsyncSocket, SocketAddr};
+use rtnetlink::constants::{
+    RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV4_RULE, RTMGRP_IPV6_IFADDR, RTMGRP_IPV6_ROUTE, RTMGRP_LINK,
+};
+use rtnetlink::new_connection;
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
-pub async fn watch_network(_env: Arc<Mutex<super::Environment>>) {
-    // Simplified network watching
-    // In a full implementation, this would use netlink subscriptions
-    // to monitor address and link changes
+const IFF_UP: u32 = 0x1;
+
+// IPv6 rules have no legacy RTMGRP_* mask, the group is joined by number
+const RTNLGRP_IPV6_RULE: u32 = 19;
+
+// Events tend to arrive in bursts (e.g. a hot-attached NIC emits link, address
+// and route notifications at once), so collect them before reconfiguring.
+const EVENT_SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NetworkEvent {
+    /// Link appeared or changed state
+    LinkChanged { if_index: u32, up: bool },
+    /// Link removed
+    LinkRemoved { if_index: u32 },
+    /// Address removed from link
+    AddressRemoved { if_index: u32 },
+    /// Route removed from a routing table
+    RouteRemoved { table: u32 },
+    /// Routing policy rule removed
+    RuleRemoved { table: u32 },
+}
+
+pub async fn watch_network(env: Arc<Mutex<super::Environment>>) {
+    let (mut connection, _, mut messages) = match new_connection() {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("Failed to create netlink connection for network watch: {}", e);
+            return;
+        }
+    };
+
+    let groups = RTMGRP_LINK
+        | RTMGRP_IPV4_IFADDR
+        | RTMGRP_IPV4_ROUTE
+        | RTMGRP_IPV4_RULE
+        | RTMGRP_IPV6_IFADDR
+        | RTMGRP_IPV6_ROUTE;
+    let socket = connection.socket_mut().socket_mut();
+    if let Err(e) = socket.bind(&SocketAddr::new(0, groups)) {
+        tracing::error!("Failed to subscribe to netlink multicast groups: {}", e);
+        return;
+    }
+
+    if let Err(e) = socket.add_membership(RTNLGRP_IPV6_RULE) {
+        tracing::error!("Failed to subscribe to IPv6 rule notifications: {}", e);
+        return;
+    }
+
+    tokio::spawn(connection);
+
     tracing::info!("Network watching started");
 
-    // Placeholder for now - full implementation would use rtnetlink subscriptions
-    tokio::spawn(async {
-        loop {
-            tokio::time::sleep(std::time::Duration::from_secs(30)).await;
-            tracing::debug!("Network watch tick");
+    tokio::spawn(async move {
+        while let Some((message, _)) = messages.next().await {
+            let mut events = Vec::new();
+            if let NetlinkPayload::InnerMessage(msg) = message.payload {
+                events.extend(parse_event(msg));
+            }
+
+            // Drain whatever else arrives while the burst settles
+            while let Ok(Some((message, _))) =
+                tokio::time::timeout(EVENT_SETTLE_DELAY, messages.next()).await
+            {
+                if let NetlinkPayload::InnerMessage(msg) = message.payload {
+                    events.extend(parse_event(msg));
+                }
+            }
+
+            if events.is_empty() {
+                continue;
+            }
+
+            let mut env_guard = env.lock().await;
+            if let Err(e) = handle_events(&mut env_guard, events).await {
+                tracing::error!("Failed to handle network events: {}", e);
+            }
         }
+
+        tracing::warn!("Netlink event stream closed, network watching stopped");
     });
 }
+
+fn parse_event(msg: RtnlMessage) -> Option<NetworkEvent> {
+    match msg {
+        // Enslaved links (e.g. Azure accelerated networking VFs) are never managed
+        RtnlMessage::NewLink(link_msg)
+            if link_msg
+                .nlas
+                .iter()
+                .any(|nla| matches!(nla, netlink_packet_route::link::nlas::Nla::Master(_))) =>
+        {
+            None
+        }
+        RtnlMessage::NewLink(link_msg) => Some(NetworkEvent::LinkChanged {
+            if_index: link_msg.header.index,
+            up: link_msg.header.flags & IFF_UP != 0,
+        }),
+        RtnlMessage::DelLink(link_msg) => Some(NetworkEvent::LinkRemoved {
+            if_index: link_msg.header.index,
+        }),
+        RtnlMessage::DelAddress(addr_msg) => Some(NetworkEvent::AddressRemoved {
+            if_index: addr_msg.header.index,
+        }),
+        RtnlMessage::DelRoute(route_msg) => Some(NetworkEvent::RouteRemoved {
+            table: crate::network::route_table_id(&route_msg),
+        }),
+        RtnlMessage::DelRule(rule_msg) => Some(NetworkEvent::RuleRemoved {
+            table: crate::network::rule_table_id(&rule_msg),
+        }),
+        _ => None,
+    }
+}
+
+async fn handle_events(env: &mut super::Environment, events: Vec<NetworkEvent>) -> Result<()> {
+    let known_links = env.links.clone();
+    let mut refetch = false;
+    let mut pending: HashSet<u32> = HashSet::new();
+
+    for event in events {
+        match event {
+            NetworkEvent::LinkChanged { if_index, up } => {
+                if !up {
+                    continue;
+                }
+
+                match known_links.links_by_mac.values().find(|l| l.ifindex == if_index) {
+                    // Link came up
+                    Some(link) if link.oper_state != "Up" => {
+                        pending.insert(if_index);
+                    }
+                    Some(_) => {}
+                    None if known_links.is_enslaved(if_index) => {}
+                    // New link, metadata may not know about it yet
+                    None => {
+                        env.netlink.invalidate_links();
+                        refetch = true;
+                        pending.insert(if_index);
+                    }
+                }
+            }
+            NetworkEvent::LinkRemoved { if_index } => {
+                tracing::debug!("Link ifindex='{}' removed", if_index);
+                env.netlink.invalidate_links();
+            }
+            NetworkEvent::AddressRemoved { if_index } => {
+                if is_managed_link(env, if_index) {
+                    pending.insert(if_index);
+                }
+            }
+            NetworkEvent::RouteRemoved { table } | NetworkEvent::RuleRemoved { table } => {
+                if let Some(if_index) = find_link_index_by_table(env, table) {
+                    pending.insert(if_index);
+                }
+            }
+        }
+    }
+
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    if refetch {
+        tracing::debug!("New link detected, refreshing ({}) metadata", env.kind);
+        super::acquire_cloud_metadata(env).await?;
+    } else {
+        env.links = crate::network::acquire_links(&env.netlink).await?;
+    }
+
+    for if_index in pending {
+        let link = match env.links.links_by_mac.values().find(|l| l.ifindex == if_index) {
+            Some(link) => link.clone(),
+            None => continue,
+        };
+
+        tracing::info!(
+            "Network change detected on link='{}' ifindex='{}', reconfiguring ...",
+            link.name, link.ifindex
+        );
+
+        if let Err(e) = super::configure_link_metadata(env, &link).await {
+            tracing::error!(
+                "Failed to reconfigure link='{}' ifindex='{}': {}",
+                link.name, link.ifindex, e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the daemon configured anything on the link, by either family
+fn is_managed_link(env: &super::Environment, if_index: u32) -> bool {
+    let has_addresses = env
+        .links
+        .links_by_mac
+        .values()
+        .find(|link| link.ifindex == if_index)
+        .and_then(|link| env.addresses_by_mac.get(&link.mac))
+        .is_some_and(|addresses| !addresses.is_empty());
+
+    has_addresses
+        || env.routes_by_index.contains_key(&if_index)
+        || env.ipv6_routes_by_index.contains_key(&if_index)
+}
+
+fn find_link_index_by_table(env: &super::Environment, table: u32) -> Option<u32> {
+    // Routes and rules of a link share one table derived from the table base
+    env.links
+        .links_by_mac
+        .values()
+        .map(|link| link.ifindex)
+        .find(|if_index| env.link_table(*if_index) == table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud::CloudProvider as CloudKind;
+    use crate::network::{Link, Route};
+    use netlink_packet_route::{route::nlas::Nla, rule, AddressMessage, LinkMessage, RouteMessage, RuleMessage};
+    use std::collections::HashMap;
+
+    fn test_env() -> super::super::Environment {
+        let config = crate::conf::Config::default();
+        let mut env = super::super::Environment::new(CloudKind::AWS, &config).unwrap();
+        for (mac, ifindex) in [("02:00:00:00:00:01", 2), ("02:00:00:00:00:02", 3)] {
+            env.links.insert(Link {
+                name: format!("eth{}", ifindex - 2),
+                ifindex,
+                oper_state: "Up".to_string(),
+                mac: mac.to_string(),
+                mtu: 1500,
+                addresses: None,
+                driver: "ena".to_string(),
+                kind: None,
+                master: None,
+            });
+        }
+        env
+    }
+
+    #[test]
+    fn test_route_event_table_above_255() {
+        // The header only has room for RT_TABLE_COMPAT, the real id is in RTA_TABLE
+        let mut route_msg = RouteMessage::default();
+        route_msg.header.table = 252;
+        route_msg.nlas.push(Nla::Table(10002));
+
+        assert_eq!(
+            parse_event(RtnlMessage::DelRoute(route_msg)),
+            Some(NetworkEvent::RouteRemoved { table: 10002 })
+        );
+    }
+
+    #[test]
+    fn test_address_removed_event() {
+        let mut addr_msg = AddressMessage::default();
+        addr_msg.header.index = 3;
+
+        assert_eq!(
+            parse_event(RtnlMessage::DelAddress(addr_msg)),
+            Some(NetworkEvent::AddressRemoved { if_index: 3 })
+        );
+
+        // Links the daemon only configured IPv6 on, or only addresses on,
+        // are reconfigured too
+        let mut env = test_env();
+        assert!(!is_managed_link(&env, 3));
+
+        let gw = Route { table: env.link_table(3), if_index: 3, gw: "fe80::1".to_string() };
+        env.ipv6_routes_by_index.insert(3, gw);
+        assert!(is_managed_link(&env, 3));
+
+        env.addresses_by_mac.insert(
+            "02:00:00:00:00:01".to_string(),
+            HashMap::from([("10.0.0.5/24".to_string(), true)]),
+        );
+        assert!(is_managed_link(&env, 2));
+    }
+
+    #[test]
+    fn test_rule_removed_event() {
+        let env = test_env();
+        let table = env.link_table(3);
+
+        let mut rule_msg = RuleMessage::default();
+        rule_msg.header.table = 252;
+        rule_msg.nlas.push(rule::nlas::Nla::Table(table));
+
+        assert_eq!(
+            parse_event(RtnlMessage::DelRule(rule_msg)),
+            Some(NetworkEvent::RuleRemoved { table })
+        );
+        assert_eq!(find_link_index_by_table(&env, table), Some(3));
+        assert_eq!(find_link_index_by_table(&env, table + 100), None);
+    }
+
+    #[test]
+    fn test_link_removed_event() {
+        let mut link_msg = LinkMessage::default();
+        link_msg.header.index = 3;
+
+        assert_eq!(
+            parse_event(RtnlMessage::DelLink(link_msg)),
+            Some(NetworkEvent::LinkRemoved { if_index: 3 })
+        );
+    }
+}
@@ -1,4 +1,3 @@
-
 // SPDX-License-Identifier: LGPL-3.0-or-later
 
 use anyhow::{anyhow, Result};
@@ -10,27 +9,189 @@ use serde::Serialize;
 use std::collections::HashMap;
 use std::time::Duration;
 
+mod unix;
+pub use unix::*;
+
 const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub async fn dispatch(url: &str, headers: HashMap<String, String>) -> Result<Vec<u8>> {
-    let client = reqwest::Client::builder()
-        .timeout(DEFAULT_REQUEST_TIMEOUT)
-        .build()?;
+// Upper bound for a single backoff delay
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Error for a request that completed with a non-success status, so callers
+/// can react to specific codes (e.g. 401 for an expired IMDSv2 token)
+#[derive(Debug, thiserror::Error)]
+#[error("HTTP request to '{url}' failed with status {status}")]
+pub struct HttpStatusError {
+    pub url: String,
+    pub status: reqwest::StatusCode,
+}
 
-    let mut request = client.get(url);
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+}
 
-    for (key, value) in headers {
-        request = request.header(key, value);
+impl RetryPolicy {
+    pub fn from_config(config: &crate::conf::Config) -> Self {
+        Self {
+            max_attempts: if config.metadata.retry.enabled {
+                config.metadata.retry.max_attempts.max(1)
+            } else {
+                1
+            },
+            backoff: config.get_retry_backoff(),
+        }
     }
 
-    let response = request
-        .send()
-        .await
-        .map_err(|e| anyhow!("Could not complete HTTP request: {}", e))?;
+    /// Exponential backoff with up to 50% random jitter, so instances booted
+    /// together do not hammer the metadata service in lockstep
+    fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.backoff.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        let delay = exp.min(MAX_RETRY_BACKOFF);
+        delay + delay.mul_f64(jitter() * 0.5)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::from_config(&crate::conf::Config::default())
+    }
+}
+
+/// Random fraction in [0, 1), independent per caller so clients started
+/// together spread out
+fn jitter() -> f64 {
+    rand::random::<f64>()
+}
+
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_retryable_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout()
+}
+
+/// HTTP client shared by the metadata providers. Applies the configured
+/// request timeout and retries connect errors, timeouts, 5xx and 429 responses.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    retry: RetryPolicy,
+    /// Provider label for the metadata request metrics, unset for requests
+    /// that are not metadata fetches
+    metrics_provider: Option<&'static str>,
+}
+
+impl HttpClient {
+    pub fn new(timeout: Duration, retry: RetryPolicy) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new());
+
+        Self {
+            client,
+            retry,
+            metrics_provider: None,
+        }
+    }
+
+    pub fn from_config(config: &crate::conf::Config) -> Self {
+        Self::new(config.get_request_timeout(), RetryPolicy::from_config(config))
+    }
+
+    /// Counts every attempt in the metadata metrics of `provider`
+    pub fn with_metrics_provider(mut self, provider: &'static str) -> Self {
+        self.metrics_provider = Some(provider);
+        self
+    }
+
+    /// Same client making a single attempt, for requests with a fallback
+    pub fn without_retry(&self) -> Self {
+        Self {
+            retry: RetryPolicy {
+                max_attempts: 1,
+                ..self.retry.clone()
+            },
+            ..self.clone()
+        }
+    }
+
+    /// Sends the request built by `build`, retrying transient failures. Any
+    /// non-success status is returned as `HttpStatusError`.
+    pub async fn send<F>(&self, build: F) -> Result<reqwest::Response>
+    where
+        F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 1;
 
-    if response.status() != reqwest::StatusCode::OK {
-        return Err(anyhow!("Non-200 status code: {}", response.status()));
+        loop {
+            let request = build(&self.client).build()?;
+            let url = request.url().to_string();
+
+            if let Some(provider) = self.metrics_provider {
+                crate::metrics::METRICS.metadata_request_attempt(provider);
+            }
+
+            let retryable = match self.client.execute(request).await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    let status = response.status();
+                    if !is_retryable_status(status) || attempt >= self.retry.max_attempts {
+                        return Err(HttpStatusError { url, status }.into());
+                    }
+                    format!("status {}", status)
+                }
+                Err(e) => {
+                    if !is_retryable_error(&e) || attempt >= self.retry.max_attempts {
+                        return Err(anyhow!("Could not complete HTTP request: {}", e));
+                    }
+                    e.to_string()
+                }
+            };
+
+            let delay = self.retry.delay(attempt);
+            tracing::debug!(
+                "HTTP request to '{}' failed ({}), retrying in {:?} (attempt {}/{})",
+                url, retryable, delay, attempt, self.retry.max_attempts
+            );
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
     }
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new(DEFAULT_REQUEST_TIMEOUT, RetryPolicy::default())
+    }
+}
+
+pub async fn dispatch(
+    client: &HttpClient,
+    method: reqwest::Method,
+    url: &str,
+    headers: &HashMap<String, String>,
+    body: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+    let response = client
+        .send(|client| {
+            let mut request = client.request(method.clone(), url);
+
+            for (key, value) in headers {
+                request = request.header(key, value);
+            }
+
+            if let Some(body) = body {
+                request = request.body(body.to_vec());
+            }
+
+            request
+        })
+        .await?;
 
     let body = response
         .bytes()
@@ -40,8 +201,100 @@ pub async fn dispatch(url: &str, headers: HashMap<String, String>) -> Result<Vec
     Ok(body.to_vec())
 }
 
+/// Client for a metadata service rooted at `base_url`, sending the headers
+/// the service requires (e.g. Metadata: true) with every request. Clones
+/// share the underlying connection pool.
+#[derive(Debug, Clone)]
+pub struct MetadataClient {
+    client: HttpClient,
+    base_url: String,
+    headers: HashMap<String, String>,
+}
+
+impl MetadataClient {
+    pub fn new(client: HttpClient, base_url: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            headers: HashMap::new(),
+        }
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn http_client(&self) -> &HttpClient {
+        &self.client
+    }
+
+    pub fn without_retry(&self) -> Self {
+        Self {
+            client: self.client.without_retry(),
+            ..self.clone()
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path.trim_start_matches('/'))
+    }
+
+    pub async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        headers: &HashMap<String, String>,
+        body: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let mut all_headers = self.headers.clone();
+        all_headers.extend(headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        dispatch(&self.client, method, &self.url(path), &all_headers, body).await
+    }
+
+    pub async fn get(&self, path: &str) -> Result<Vec<u8>> {
+        self.request(reqwest::Method::GET, path, &HashMap::new(), None).await
+    }
+
+    pub async fn get_text(&self, path: &str) -> Result<String> {
+        let body = self.get(path).await?;
+        Ok(String::from_utf8_lossy(&body).trim().to_string())
+    }
+
+    pub async fn get_json<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let body = self.get(path).await?;
+        serde_json::from_slice(&body).map_err(|e| anyhow!("Failed to parse '{}': {}", self.url(path), e))
+    }
+
+    /// GET returning the whole response, for callers that need its headers
+    /// (e.g. ETag). `timeout` replaces the client's one, for long-polls.
+    pub async fn get_response(&self, path: &str, timeout: Duration) -> Result<reqwest::Response> {
+        let url = self.url(path);
+
+        self.client
+            .send(|client| {
+                let mut request = client.get(&url).timeout(timeout);
+
+                for (key, value) in &self.headers {
+                    request = request.header(key, value);
+                }
+
+                request
+            })
+            .await
+    }
+
+    pub async fn post_json<T: Serialize>(&self, path: &str, data: &T) -> Result<Vec<u8>> {
+        let body = serde_json::to_vec(data)?;
+        let headers = HashMap::from([("Content-Type".to_string(), "application/json".to_string())]);
+        self.request(reqwest::Method::POST, path, &headers, Some(&body)).await
+    }
+}
+
 pub fn json_response<T: Serialize>(data: &T) -> Response {
-    match serde_json::to_string(data) {
+    // Serialize to a value, wrapping a string in Json would encode it twice
+    match serde_json::to_value(data) {
         Ok(json) => (StatusCode::OK, Json(json)).into_response(),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -50,3 +303,103 @@ pub fn json_response<T: Serialize>(data: &T) -> Response {
             .into_response(),
     }
 }
+
+/// JSON error body in the same shape as the control endpoints use
+pub fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(serde_json::json!({ "status": "error", "message": message }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = RetryPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_secs(1),
+        };
+
+        for (attempt, base) in [(1, 1), (2, 2), (3, 4), (10, 60)] {
+            let delay = retry.delay(attempt);
+            assert!(delay >= Duration::from_secs(base));
+            assert!(delay <= Duration::from_secs(base).mul_f64(1.5));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metadata_client_retry() {
+        let mut server = mockito::Server::new_async().await;
+
+        let unavailable = server
+            .mock("GET", "/latest/meta-data/instance-id")
+            .with_status(503)
+            .expect(3)
+            .create_async()
+            .await;
+
+        let client = MetadataClient::new(
+            HttpClient::new(
+                Duration::from_secs(5),
+                RetryPolicy {
+                    max_attempts: 3,
+                    backoff: Duration::from_millis(10),
+                },
+            )
+            .with_metrics_provider("retry-test"),
+            &format!("{}/latest/", server.url()),
+        )
+        .with_header("Metadata", "true");
+
+        assert!(client.get_text("meta-data/instance-id").await.is_err());
+        unavailable.assert_async().await;
+        assert!(crate::metrics::METRICS
+            .render()
+            .contains("cloud_netconfig_metadata_fetch_attempts_total{provider=\"retry-test\"} 3"));
+        unavailable.remove_async().await;
+
+        server
+            .mock("GET", "/latest/meta-data/instance-id")
+            .match_header("Metadata", "true")
+            .with_body("i-0123456789\n")
+            .create_async()
+            .await;
+
+        assert_eq!(client.get_text("meta-data/instance-id").await.unwrap(), "i-0123456789");
+    }
+
+    #[test]
+    fn test_retryable_status() {
+        assert!(is_retryable_status(reqwest::StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retryable_status(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable_status(reqwest::StatusCode::NOT_FOUND));
+        assert!(!is_retryable_status(reqwest::StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn test_not_found_is_not_retried() {
+        let mut server = mockito::Server::new_async().await;
+
+        let not_found = server
+            .mock("GET", "/latest/meta-data/public-ipv4")
+            .with_status(404)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = MetadataClient::new(
+            HttpClient::new(
+                Duration::from_secs(5),
+                RetryPolicy {
+                    max_attempts: 3,
+                    backoff: Duration::from_millis(10),
+                },
+            ),
+            &format!("{}/latest/", server.url()),
+        );
+
+        let e = client.get_text("meta-data/public-ipv4").await.unwrap_err();
+        assert_eq!(e.downcast_ref::<HttpStatusError>().unwrap().status, reqwest::StatusCode::NOT_FOUND);
+        not_found.assert_async().await;
+    }
+}
@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+    Router,
+};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper_util::rt::TokioIo;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{UnixListener, UnixStream};
+use tower::ServiceExt;
+
+/// Credentials of the process on the other end of a socket connection, as
+/// reported by SO_PEERCRED. Attached to every request served on the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    pub fn from_stream(stream: &UnixStream) -> std::io::Result<Self> {
+        let cred = stream.peer_cred()?;
+        Ok(Self {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        })
+    }
+}
+
+/// Peers allowed to call mutating endpoints
+#[derive(Debug, Clone, Default)]
+pub struct SocketAccess {
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+}
+
+impl SocketAccess {
+    pub fn from_config(config: &crate::conf::Config) -> Self {
+        Self {
+            allowed_uids: config.server.unix_socket.allowed_uids.clone(),
+            allowed_gids: config.server.unix_socket.allowed_gids.clone(),
+        }
+    }
+
+    pub fn is_allowed(&self, peer: &PeerCredentials) -> bool {
+        self.allowed_uids.contains(&peer.uid) || self.allowed_gids.contains(&peer.gid)
+    }
+}
+
+/// Middleware letting reads through and requiring an allowed socket peer for
+/// everything else. Requests over TCP carry no credentials, so they can never
+/// change anything.
+pub async fn authorize(
+    State(access): State<Arc<SocketAccess>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+
+    match request.extensions().get::<PeerCredentials>() {
+        Some(peer) if access.is_allowed(peer) => next.run(request).await,
+        Some(peer) => {
+            tracing::warn!(
+                "Rejected {} {} from uid='{}' gid='{}' pid='{}'",
+                request.method(),
+                request.uri().path(),
+                peer.uid,
+                peer.gid,
+                peer.pid.unwrap_or_default()
+            );
+            super::error_response(
+                StatusCode::FORBIDDEN,
+                "Peer is not allowed to change the network configuration",
+            )
+        }
+        None => super::error_response(
+            StatusCode::FORBIDDEN,
+            "Changes are only accepted on the Unix socket, see server.unix_socket",
+        ),
+    }
+}
+
+/// Binds the API socket, replacing one left behind by a previous run. The
+/// socket is world accessible, mutating calls are checked per peer.
+pub fn bind_unix(path: &str) -> Result<UnixListener> {
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket '{}'", path))?;
+    }
+
+    if let Some(dir) = Path::new(path).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed to bind '{}'", path))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))?;
+
+    Ok(listener)
+}
+
+/// Serves the router on the socket until the listener fails
+pub async fn serve_unix(listener: UnixListener, router: Router) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let peer = match PeerCredentials::from_stream(&stream) {
+            Ok(peer) => peer,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to read socket peer credentials, dropping connection: {}",
+                    e
+                );
+                continue;
+            }
+        };
+
+        let router = router.clone();
+        tokio::spawn(async move {
+            let service =
+                hyper::service::service_fn(move |mut request: Request<hyper::body::Incoming>| {
+                    request.extensions_mut().insert(peer);
+                    router.clone().oneshot(request)
+                });
+
+            if let Err(e) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Socket connection from uid='{}' failed: {}", peer.uid, e);
+            }
+        });
+    }
+}
+
+/// Sends a request without body over the API socket and returns the body of
+/// the response
+pub async fn unix_request(socket: &str, method: Method, path: &str) -> Result<Vec<u8>> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Failed to connect to '{}'", socket))?;
+
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let request = hyper::Request::builder()
+        .method(method)
+        .uri(path)
+        .header(hyper::header::HOST, "localhost")
+        .body(Empty::<Bytes>::new())?;
+
+    let response = sender.send_request(request).await?;
+    let body = response.into_body().collect().await?.to_bytes();
+
+    Ok(body.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::{get, post};
+
+    #[tokio::test]
+    async fn test_socket_authorization() {
+        let dir = std::env::temp_dir().join(format!("cloud-network-test-{}", std::process::id()));
+        let socket = dir.join("api.sock").to_string_lossy().to_string();
+
+        let uid = nix::unistd::getuid().as_raw();
+        let access = |allowed_uids: Vec<u32>| {
+            Arc::new(SocketAccess {
+                allowed_uids,
+                allowed_gids: Vec::new(),
+            })
+        };
+
+        for (allowed, expected) in [
+            (vec![uid], "\"changed\""),
+            (vec![uid + 1], "\"status\":\"error\""),
+        ] {
+            let router = Router::new()
+                .route("/read", get(|| async { "read" }))
+                .route("/write", post(|| async { "\"changed\"" }))
+                .layer(axum::middleware::from_fn_with_state(
+                    access(allowed),
+                    authorize,
+                ));
+
+            let listener = bind_unix(&socket).unwrap();
+            let server = tokio::spawn(serve_unix(listener, router));
+
+            let read = unix_request(&socket, Method::GET, "/read").await.unwrap();
+            assert_eq!(read, b"read");
+
+            let write = unix_request(&socket, Method::POST, "/write").await.unwrap();
+            assert!(String::from_utf8_lossy(&write).contains(expected));
+
+            server.abort();
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_changes_are_rejected() {
+        // Default config, the socket is disabled
+        let access = Arc::new(SocketAccess::from_config(&crate::conf::Config::default()));
+        let router = Router::new()
+            .route("/read", get(|| async { "read" }))
+            .route("/write", post(|| async { "changed" }))
+            .layer(axum::middleware::from_fn_with_state(access, authorize));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = reqwest::Client::new();
+
+        let read = client.get(format!("http://{}/read", addr)).send().await.unwrap();
+        assert_eq!(read.status(), reqwest::StatusCode::OK);
+
+        let write = client.post(format!("http://{}/write", addr)).send().await.unwrap();
+        assert_eq!(write.status(), reqwest::StatusCode::FORBIDDEN);
+
+        server.abort();
+    }
+}